@@ -1,27 +1,428 @@
-use pulldown_cmark::{Options, Parser};
+use pulldown_cmark::{Event, Parser, Tag};
+use serde::Deserialize;
 use std::{
-    collections::HashMap,
     fs,
     io::{self, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
+#[allow(dead_code)]
+#[path = "src/markdown.rs"]
+mod markdown;
+
+const GUIDES_SRC_DIR: &str = "markdown/guides/";
+const GUIDES_DST_DIR: &str = "templates/guides/gen/";
+
+// Community guides are served from `/guides/community/`, so no guide may use it
+const RESERVED_SLUG: &str = "community";
+
 struct Info {
-    name: &'static str,
-    dst_dir: &'static str,
-    typename: &'static str,
+    slug: String,
+    src_path: PathBuf,
+    typename: String,
 }
 
 impl Info {
-    pub fn new(a: &'static str, b: &'static str, c: &'static str) -> Self {
+    // `markdown/guides/weapons/longsword.md` becomes the slug `weapons/longsword`
+    // and the type `GuideWeaponsLongsword`
+    pub fn new(src_path: PathBuf) -> Self {
+        let slug = src_path
+            .strip_prefix(GUIDES_SRC_DIR)
+            .unwrap()
+            .with_extension("")
+            .components()
+            .map(|c| c.as_os_str().to_str().unwrap())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let typename = slug
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| word[..1].to_uppercase() + &word[1..])
+            .collect::<String>();
+
         Self {
-            name: a,
-            dst_dir: b,
-            typename: c,
+            slug,
+            src_path,
+            typename: format!("Guide{}", typename),
+        }
+    }
+}
+
+// Metadata from the `+++` delimited TOML block at the top of a guide, e.g:
+//
+// +++
+// title = "Longsword Basics"
+// author = 76561197960287930
+// summary = "Everything you need to know to get started with the longsword."
+// tags = ["beginner", "weapons"]
+// published = 2019-06-20
+// draft = true
+// weapons = ["Longsword"]
+// +++
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FrontMatter {
+    title: Option<String>,
+    author: Option<u64>, // Steam ID
+    summary: Option<String>,
+    tags: Vec<String>,
+    published: Option<toml::value::Datetime>,
+    updated: Option<toml::value::Datetime>,
+    draft: bool,
+    weapons: Vec<String>,
+}
+
+struct Guide {
+    info: Info,
+    meta: FrontMatter,
+    title: String,
+    description: Option<String>,
+    // Plain text of the guide, for site search
+    text: String,
+    rendered: Rendered,
+}
+
+impl Guide {
+    fn published(&self) -> Option<String> {
+        self.meta.published.as_ref().map(ToString::to_string)
+    }
+
+    fn updated(&self) -> Option<String> {
+        self.meta.updated.as_ref().map(ToString::to_string)
+    }
+}
+
+// Split the front-matter block, if any, off the start of a guide
+fn split_front_matter(markdown: &str) -> (Option<&str>, &str) {
+    let trimmed = markdown.trim_start();
+    if !trimmed.starts_with("+++") {
+        return (None, markdown);
+    }
+
+    let after = &trimmed[3..];
+    match after.find("\n+++") {
+        Some(end) => (Some(&after[..end]), &after[end + 4..]),
+        None => (None, markdown),
+    }
+}
+
+// Format a list of strings as a static slice literal
+fn str_slice(items: &[String]) -> String {
+    let items = items
+        .iter()
+        .map(|item| format!("{:?}", item))
+        .collect::<Vec<_>>();
+
+    format!("&[{}]", items.join(", "))
+}
+
+// Matches `equipment::normalize` in the main crate, used for catalog links
+fn weapon_slug(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Recursively find all markdown files in `dir`. Cargo only notices files
+// being added to or removed from a folder it's told to watch directly, so
+// every folder is watched, not just the top one.
+fn discover(dir: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    println!("cargo:rerun-if-changed={}", dir.display());
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            discover(&path, out)?;
+        } else if path.extension().map_or(false, |ext| ext == "md") {
+            out.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+// Pull the text of the first heading and first paragraph out of a guide, for
+// use as the page's OpenGraph title and description
+fn extract_meta(events: &[Event]) -> (Option<String>, Option<String>) {
+    let mut title: Option<String> = None;
+    let mut description: Option<String> = None;
+    let mut current: Option<String> = None;
+
+    for event in events {
+        match event {
+            Event::Start(Tag::Header(_)) if title.is_none() => current = Some(String::new()),
+            Event::Start(Tag::Paragraph) if description.is_none() => current = Some(String::new()),
+            Event::Text(text) | Event::Code(text) => {
+                if let Some(buf) = current.as_mut() {
+                    buf.push_str(text);
+                }
+            }
+            Event::End(Tag::Header(_)) if title.is_none() => title = current.take(),
+            Event::End(Tag::Paragraph) if description.is_none() => {
+                // Skip over paragraphs that are just askama blocks, like `page_title`
+                description = current.take().filter(|d| !d.trim_start().starts_with("{%"))
+            }
+            _ => {}
+        }
+    }
+
+    (title, description)
+}
+
+// Strip the formatting from a guide, leaving just the words
+fn plain_text(events: &[Event]) -> String {
+    let mut out = String::new();
+
+    for event in events {
+        match event {
+            // Skip over askama blocks, like `page_title`
+            Event::Text(text) if text.trim_start().starts_with("{%") => {}
+            Event::Text(text) | Event::Code(text) => out.push_str(text),
+            Event::SoftBreak | Event::HardBreak | Event::End(_) => out.push(' '),
+            _ => {}
+        }
+    }
+
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+struct TocEntry {
+    level: i32,
+    id: String,
+    text: String,
+}
+
+// The output of the markdown pipeline for a single guide
+struct Rendered {
+    html: String,
+    toc: Vec<TocEntry>,
+    // Loadouts embedded with `{{loadout 42}}`, fetched when the page is rendered
+    loadout_ids: Vec<i32>,
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        // Stop askama from treating literal braces as template syntax
+        .replace('{', "&#123;")
+        .replace('}', "&#125;")
+}
+
+// pulldown-cmark writes inline code out as is, apart from escaping html, so
+// braces in it would be read by askama as template syntax
+fn escape_inline_code(event: Event) -> Event {
+    match event {
+        Event::Code(code) => Event::Html(format!("<code>{}</code>", escape_html(&code)).into()),
+        event => event,
+    }
+}
+
+// Turn heading text into a unique anchor, e.g `Getting Started` into `getting-started`
+fn anchor(text: &str, used: &mut Vec<String>) -> String {
+    let mut id = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            id.push(c);
+        } else if !id.ends_with('-') {
+            id.push('-');
+        }
+    }
+    let base = id.trim_matches('-').to_owned();
+
+    let mut id = base.clone();
+    let mut n = 1;
+    while used.contains(&id) {
+        id = format!("{}-{}", base, n);
+        n += 1;
+    }
+
+    used.push(id.clone());
+    id
+}
+
+// Expand `{{loadout 42}}` and `{{weapon Zweihander}}` shortcodes into calls to
+// `routes::guides::Embeds`. Everything else is escaped, including braces, so
+// that askama shows it as is.
+fn expand_shortcodes<'a>(text: &str, out: &mut Vec<Event<'a>>, loadout_ids: &mut Vec<i32>) {
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        if start > 0 {
+            out.push(Event::Html(escape_html(&rest[..start]).into()));
+        }
+
+        let inner = rest[start + 2..end].trim();
+        let mut words = inner.splitn(2, char::is_whitespace);
+        let code = match (words.next(), words.next().map(str::trim)) {
+            (Some("loadout"), Some(id)) => id.parse::<i32>().ok().map(|id| {
+                loadout_ids.push(id);
+                format!("{{{{ embeds.loadout_card({})|safe }}}}", id)
+            }),
+            (Some("weapon"), Some(name)) if !name.contains('"') => {
+                Some(format!("{{{{ embeds.weapon_link({:?})|safe }}}}", name))
+            }
+            _ => None,
+        };
+
+        match code {
+            Some(code) => out.push(Event::Html(code.into())),
+            None => out.push(Event::Html(escape_html(&rest[start..end + 2]).into())),
         }
+
+        rest = &rest[end + 2..];
+    }
+
+    if !rest.is_empty() {
+        out.push(Event::Html(escape_html(rest).into()));
     }
 }
 
+// Add heading anchors, build the table of contents and expand shortcodes
+fn render(events: Vec<Event>) -> Rendered {
+    let mut out = Vec::new();
+    let mut toc = Vec::new();
+    let mut loadout_ids = Vec::new();
+    let mut used_ids = Vec::new();
+
+    let mut heading: Option<(i32, Vec<Event>)> = None;
+    // The source, title and alt text of the image being read
+    let mut image: Option<(String, String, String)> = None;
+    let mut in_code_block = false;
+
+    // Merge adjacent text events so that shortcodes aren't split in two
+    let mut merged: Vec<Event> = Vec::new();
+    for event in events {
+        if let Event::Text(text) = &event {
+            if let Some(Event::Text(prev)) = merged.last_mut() {
+                *prev = format!("{}{}", &**prev, &**text).into();
+                continue;
+            }
+        }
+
+        merged.push(event);
+    }
+
+    for event in merged {
+        match event {
+            Event::Start(Tag::Header(level)) => heading = Some((level, Vec::new())),
+            Event::End(Tag::Header(_)) => {
+                let (level, inner) = heading.take().unwrap();
+                let text = inner
+                    .iter()
+                    .filter_map(|e| match e {
+                        Event::Text(text) | Event::Code(text) => Some((**text).to_owned()),
+                        _ => None,
+                    })
+                    .collect::<String>();
+                let id = anchor(&text, &mut used_ids);
+
+                out.push(Event::Html(format!("<h{} id=\"{}\">", level, id).into()));
+                for event in inner {
+                    match event {
+                        Event::Text(text) => expand_shortcodes(&text, &mut out, &mut loadout_ids),
+                        event => out.push(escape_inline_code(event)),
+                    }
+                }
+                out.push(Event::Html(format!("</h{}>\n", level).into()));
+
+                toc.push(TocEntry { level, id, text });
+            }
+            Event::Start(Tag::CodeBlock(lang)) => {
+                in_code_block = true;
+                out.push(Event::Start(Tag::CodeBlock(lang)));
+            }
+            Event::End(Tag::CodeBlock(lang)) => {
+                in_code_block = false;
+                out.push(Event::End(Tag::CodeBlock(lang)));
+            }
+            // pulldown-cmark writes alt text out without escaping braces, and
+            // drops html from it, so images are written out here instead
+            Event::Start(Tag::Image(_, src, title)) => {
+                image = Some(((*src).to_owned(), (*title).to_owned(), String::new()))
+            }
+            Event::End(Tag::Image(..)) => {
+                let (src, title, alt) = image.take().unwrap();
+                let title = if title.is_empty() {
+                    String::new()
+                } else {
+                    format!(" title=\"{}\"", escape_html(&title))
+                };
+
+                let html = Event::Html(
+                    format!(
+                        "<img src=\"{}\" alt=\"{}\"{} />",
+                        escape_html(&src),
+                        escape_html(&alt),
+                        title
+                    )
+                    .into(),
+                );
+
+                match heading.as_mut() {
+                    Some((_, inner)) => inner.push(html),
+                    None => out.push(html),
+                }
+            }
+            Event::Text(text) | Event::Code(text) if image.is_some() => {
+                image.as_mut().unwrap().2.push_str(&text)
+            }
+            _ if image.is_some() => {}
+            Event::Text(text) if in_code_block => out.push(Event::Html(escape_html(&text).into())),
+            Event::Text(text) => match heading.as_mut() {
+                Some((_, inner)) => inner.push(Event::Text(text)),
+                None => expand_shortcodes(&text, &mut out, &mut loadout_ids),
+            },
+            event => match heading.as_mut() {
+                Some((_, inner)) => inner.push(event),
+                None => out.push(escape_inline_code(event)),
+            },
+        }
+    }
+
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, out.into_iter());
+
+    Rendered {
+        html,
+        toc,
+        loadout_ids,
+    }
+}
+
+// The page title comes from the front-matter or the first heading, so only
+// include lower level headings in the table of contents
+fn toc_html(toc: &[TocEntry]) -> String {
+    let entries = toc.iter().filter(|e| e.level > 1).collect::<Vec<_>>();
+    if entries.len() < 2 {
+        return String::new();
+    }
+
+    let mut html = String::from(
+        "<div class=\"uk-card uk-card-default uk-card-small uk-card-body uk-margin\">\n<h4>Contents</h4>\n<ul class=\"uk-list\">\n",
+    );
+    for entry in entries {
+        html += &format!(
+            "<li style=\"padding-left: {}em\"><a href=\"#{}\">{}</a></li>\n",
+            entry.level - 2,
+            entry.id,
+            escape_html(&entry.text)
+        );
+    }
+    html += "</ul>\n</div>\n";
+
+    html
+}
+
 // Compile markdown files in the markdown folder into templates for the
 // templates folder
 
@@ -30,41 +431,111 @@ fn main() -> io::Result<()> {
         return Ok(());
     }
 
-    let mut askama_structs =
-        String::from("use askama::Template;\nuse actix_web::web;\nuse crate::app::{TmplBase, ActiveLink, State};");
+    let mut askama_structs = String::from(
+        "use askama::Template;\nuse actix_web::web;\nuse futures::Future;\nuse crate::app::{TmplBase, ActiveLink, State};\nuse crate::routes::guides::{Embeds, GuideInfo, GuideWeapon};",
+    );
     let mut askama_scope = String::from(
         "\npub fn scope() -> actix_web::Scope {
     web::scope(\"/guides/\")",
     );
+    let mut guide_list = String::from("\npub static GUIDES: &[GuideInfo] = &[");
 
-    let mut options = Options::empty();
-    options.insert(Options::ENABLE_STRIKETHROUGH);
+    let options = markdown::options();
 
-    let mut mappings = HashMap::new();
-    mappings.insert(
-        "markdown/guides/",
-        Info::new("test", "templates/guides/gen/", "GuideTest"),
-    );
+    // Drafts are only visible when developing locally
+    let release = std::env::var("PROFILE").map_or(false, |p| p == "release");
 
-    for (src_dir, info) in mappings {
-        let src_path = Path::new(src_dir).join(info.name).with_extension("md");
-        let dst_path = Path::new(info.dst_dir)
-            .join(info.name)
-            .with_extension("html");
+    println!("cargo:rerun-if-changed=src/markdown.rs");
+
+    // Start from scratch, so that templates for deleted or renamed guides
+    // don't linger
+    if Path::new(GUIDES_DST_DIR).exists() {
+        fs::remove_dir_all(GUIDES_DST_DIR)?;
+    }
+
+    let mut src_paths = Vec::new();
+    discover(Path::new(GUIDES_SRC_DIR), &mut src_paths)?;
+
+    let mut guides = Vec::new();
+    for info in src_paths.into_iter().map(Info::new) {
+        println!("cargo:rerun-if-changed={}", info.src_path.to_str().unwrap()); // Tell cargo to watch this file
+
+        if info.slug == RESERVED_SLUG || info.slug.starts_with(&format!("{}/", RESERVED_SLUG)) {
+            panic!(
+                "{} can't be used: the `{}` slug is reserved for community guides",
+                info.src_path.display(),
+                RESERVED_SLUG
+            );
+        }
 
-        let _ = fs::create_dir(&info.dst_dir); // Create output folder if it doesn't already exist
+        let source = fs::read_to_string(&info.src_path)?;
+        let (front_matter, markdown) = split_front_matter(&source);
 
-        println!("cargo:rerun-if-changed={}", src_path.to_str().unwrap()); // Tell cargo to watch this file
+        let meta: FrontMatter = match front_matter {
+            Some(toml) => toml::from_str(toml).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad front-matter in {}: {}", info.src_path.display(), e),
+                )
+            })?,
+            None => FrontMatter::default(),
+        };
+
+        if meta.draft && release {
+            continue;
+        }
+
+        let events: Vec<Event> = Parser::new_ext(markdown, options).collect();
+        let (heading, paragraph) = extract_meta(&events);
+        let text = plain_text(&events);
+        let rendered = render(events);
 
-        let markdown = fs::read_to_string(&src_path)?;
-        let parser = Parser::new_ext(&markdown, options);
+        guides.push(Guide {
+            title: meta
+                .title
+                .clone()
+                .or(heading)
+                .unwrap_or_else(|| info.slug.clone()),
+            description: meta.summary.clone().or(paragraph),
+            text,
+            info,
+            meta,
+            rendered,
+        });
+    }
+
+    // Newest first, with undated guides at the end
+    guides.sort_by(|a, b| {
+        b.published()
+            .cmp(&a.published())
+            .then_with(|| a.title.cmp(&b.title))
+    });
+
+    for (index, guide) in guides.iter().enumerate() {
+        let info = &guide.info;
+        let dst_path = Path::new(GUIDES_DST_DIR)
+            .join(&info.slug)
+            .with_extension("html");
 
-        let mut html = String::new();
-        pulldown_cmark::html::push_html(&mut html, parser);
+        fs::create_dir_all(dst_path.parent().unwrap())?; // Create output folder if it doesn't already exist
+
+        let mut meta = format!(
+            ".with_canonical(\"/guides/{}\").with_title({:?})",
+            info.slug, guide.title
+        );
+        if let Some(description) = &guide.description {
+            meta += &format!(".with_description({:?})", description);
+        }
 
         let mut out = fs::File::create(dst_path)?;
-        out.write_all("{% extends \"index.html\" %}\n\n{% block content %}\n".as_bytes())?;
-        out.write_all(html.as_bytes())?;
+        out.write_all("{% extends \"index.html\" %}\n".as_bytes())?;
+        out.write_all(
+            "{% block page_title %}{{ guide.title }} | {% call super() %}{% endblock page_title %}\n\n"
+                .as_bytes(),
+        )?;
+        out.write_all("{% block content %}\n{% include \"guides/header.html\" %}\n".as_bytes())?;
+        out.write_all(toc_html(&guide.rendered.toc).as_bytes())?;
+        out.write_all(guide.rendered.html.as_bytes())?;
         out.write_all("{% endblock %}\n".as_bytes())?;
 
         askama_structs += &format!(
@@ -73,8 +544,10 @@ fn main() -> io::Result<()> {
 #[template(path = \"guides/gen/{}.html\")]
 struct {} {{
     base: TmplBase,
+    guide: &'static GuideInfo,
+    embeds: Embeds,
 }}",
-            info.name, info.typename
+            info.slug, info.typename
         );
 
         askama_scope += &format!(
@@ -84,22 +557,67 @@ struct {} {{
             web::resource(\"/{}\")
                 .route(
                     web::get()
-                    .to(|user: Option<crate::models::User>|
-                        State::render({} {{
-                            base: TmplBase::new(user, ActiveLink::Guides)
-                        }})
+                    .to_async(|user: Option<crate::models::User>, state: web::Data<State>|
+                        Embeds::fetch(&{:?}, state.get_db()).and_then(move |embeds|
+                            State::render({} {{
+                                base: TmplBase::new(user, ActiveLink::Guides){},
+                                guide: &GUIDES[{}],
+                                embeds,
+                            }})
+                        )
                     )
                 )
         )",
-            info.name, info.typename
+            info.slug, guide.rendered.loadout_ids, info.typename, meta, index
+        );
+
+        let weapons = guide
+            .meta
+            .weapons
+            .iter()
+            .map(|name| {
+                format!(
+                    "GuideWeapon {{ name: {:?}, slug: {:?} }}",
+                    name,
+                    weapon_slug(name)
+                )
+            })
+            .collect::<Vec<_>>();
+
+        guide_list += &format!(
+            "
+    GuideInfo {{
+        slug: {:?},
+        title: {:?},
+        summary: {:?},
+        author: {:?},
+        tags: {},
+        published: {:?},
+        updated: {:?},
+        draft: {},
+        weapons: &[{}],
+        text: {:?},
+    }},",
+            info.slug,
+            guide.title,
+            guide.description,
+            guide.meta.author,
+            str_slice(&guide.meta.tags),
+            guide.published(),
+            guide.updated(),
+            guide.meta.draft,
+            weapons.join(", "),
+            guide.text,
         );
     }
 
     askama_scope += "\n}";
+    guide_list += "\n];";
 
     let mut out_rust = fs::File::create("src/routes/gen/guides.rs")?;
 
-    out_rust.write_all(format!("{}\n{}\n", askama_structs, askama_scope).as_bytes())?;
+    out_rust
+        .write_all(format!("{}\n{}\n{}\n", askama_structs, askama_scope, guide_list).as_bytes())?;
 
     Ok(())
 }
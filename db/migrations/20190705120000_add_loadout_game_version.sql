@@ -0,0 +1,8 @@
+-- migrate:up
+-- Existing loadouts were all created against patch 15, the first patch with game data
+ALTER TABLE loadouts ADD COLUMN game_version VARCHAR(16) NOT NULL DEFAULT '15';
+ALTER TABLE loadouts ALTER COLUMN game_version DROP DEFAULT;
+
+
+-- migrate:down
+ALTER TABLE loadouts DROP COLUMN game_version;
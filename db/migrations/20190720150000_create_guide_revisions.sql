@@ -0,0 +1,23 @@
+-- migrate:up
+ALTER TABLE users ADD COLUMN is_moderator BOOLEAN NOT NULL DEFAULT false;
+
+CREATE TABLE guide_revisions (
+  id SERIAL PRIMARY KEY,
+  guide_id INTEGER NOT NULL REFERENCES guides(id),
+  user_id INTEGER NOT NULL REFERENCES users(id),
+  title VARCHAR(120) NOT NULL,
+  body VARCHAR NOT NULL,
+  status VARCHAR(16) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  reviewed_by INTEGER REFERENCES users(id),
+  reviewed_at TIMESTAMP
+);
+
+-- Existing guides start out with a single accepted revision
+INSERT INTO guide_revisions (guide_id, user_id, title, body, status, created_at)
+  SELECT id, user_id, title, body, 'accepted', updated_at FROM guides;
+
+
+-- migrate:down
+DROP TABLE guide_revisions;
+ALTER TABLE users DROP COLUMN is_moderator;
@@ -0,0 +1,11 @@
+-- migrate:up
+ALTER TABLE users ADD COLUMN persona_name VARCHAR;
+
+CREATE INDEX loadouts_search_idx ON loadouts USING GIN (to_tsvector('simple', name || ' ' || data));
+CREATE INDEX users_search_idx ON users USING GIN (to_tsvector('simple', COALESCE(persona_name, '')));
+
+
+-- migrate:down
+DROP INDEX users_search_idx;
+DROP INDEX loadouts_search_idx;
+ALTER TABLE users DROP COLUMN persona_name;
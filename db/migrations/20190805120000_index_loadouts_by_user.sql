@@ -0,0 +1,6 @@
+-- migrate:up
+CREATE INDEX loadouts_user_id_idx ON loadouts (user_id);
+
+
+-- migrate:down
+DROP INDEX loadouts_user_id_idx;
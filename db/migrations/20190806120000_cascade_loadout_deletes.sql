@@ -0,0 +1,14 @@
+-- migrate:up
+ALTER TABLE images DROP CONSTRAINT images_loadout_id_fkey;
+ALTER TABLE images ADD CONSTRAINT images_loadout_id_fkey FOREIGN KEY (loadout_id) REFERENCES loadouts(id) ON DELETE CASCADE;
+
+ALTER TABLE likes DROP CONSTRAINT likes_loadout_id_fkey;
+ALTER TABLE likes ADD CONSTRAINT likes_loadout_id_fkey FOREIGN KEY (loadout_id) REFERENCES loadouts(id) ON DELETE CASCADE;
+
+
+-- migrate:down
+ALTER TABLE likes DROP CONSTRAINT likes_loadout_id_fkey;
+ALTER TABLE likes ADD CONSTRAINT likes_loadout_id_fkey FOREIGN KEY (loadout_id) REFERENCES loadouts(id);
+
+ALTER TABLE images DROP CONSTRAINT images_loadout_id_fkey;
+ALTER TABLE images ADD CONSTRAINT images_loadout_id_fkey FOREIGN KEY (loadout_id) REFERENCES loadouts(id);
@@ -0,0 +1,6 @@
+-- migrate:up
+ALTER TABLE users ADD COLUMN persona_name_updated_at TIMESTAMP;
+
+
+-- migrate:down
+ALTER TABLE users DROP COLUMN persona_name_updated_at;
@@ -0,0 +1,6 @@
+-- migrate:up
+CREATE INDEX guides_search_idx ON guides USING GIN (to_tsvector('english', title || ' ' || body));
+
+
+-- migrate:down
+DROP INDEX guides_search_idx;
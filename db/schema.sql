@@ -13,6 +13,78 @@ SET default_tablespace = '';
 
 SET default_with_oids = false;
 
+--
+-- Name: guide_revisions; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.guide_revisions (
+    id integer NOT NULL,
+    guide_id integer NOT NULL,
+    user_id integer NOT NULL,
+    title character varying(120) NOT NULL,
+    body character varying NOT NULL,
+    status character varying(16) NOT NULL,
+    created_at timestamp without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    reviewed_by integer,
+    reviewed_at timestamp without time zone
+);
+
+
+--
+-- Name: guide_revisions_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+CREATE SEQUENCE public.guide_revisions_id_seq
+    AS integer
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
+
+
+--
+-- Name: guide_revisions_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: -
+--
+
+ALTER SEQUENCE public.guide_revisions_id_seq OWNED BY public.guide_revisions.id;
+
+
+--
+-- Name: guides; Type: TABLE; Schema: public; Owner: -
+--
+
+CREATE TABLE public.guides (
+    id integer NOT NULL,
+    user_id integer NOT NULL,
+    title character varying(120) NOT NULL,
+    body character varying NOT NULL,
+    published boolean DEFAULT false NOT NULL,
+    created_at timestamp without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    updated_at timestamp without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+
+--
+-- Name: guides_id_seq; Type: SEQUENCE; Schema: public; Owner: -
+--
+
+CREATE SEQUENCE public.guides_id_seq
+    AS integer
+    START WITH 1
+    INCREMENT BY 1
+    NO MINVALUE
+    NO MAXVALUE
+    CACHE 1;
+
+
+--
+-- Name: guides_id_seq; Type: SEQUENCE OWNED BY; Schema: public; Owner: -
+--
+
+ALTER SEQUENCE public.guides_id_seq OWNED BY public.guides.id;
+
+
 --
 -- Name: images; Type: TABLE; Schema: public; Owner: -
 --
@@ -86,7 +158,8 @@ CREATE TABLE public.loadouts (
     user_id integer NOT NULL,
     name character varying(80) NOT NULL,
     data character varying NOT NULL,
-    created_at timestamp without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+    created_at timestamp without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    game_version character varying(16) NOT NULL
 );
 
 
@@ -125,7 +198,10 @@ CREATE TABLE public.schema_migrations (
 
 CREATE TABLE public.users (
     id integer NOT NULL,
-    steam_id bigint NOT NULL
+    steam_id bigint NOT NULL,
+    is_moderator boolean DEFAULT false NOT NULL,
+    persona_name character varying,
+    persona_name_updated_at timestamp without time zone
 );
 
 
@@ -149,6 +225,20 @@ CREATE SEQUENCE public.users_id_seq
 ALTER SEQUENCE public.users_id_seq OWNED BY public.users.id;
 
 
+--
+-- Name: guide_revisions id; Type: DEFAULT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.guide_revisions ALTER COLUMN id SET DEFAULT nextval('public.guide_revisions_id_seq'::regclass);
+
+
+--
+-- Name: guides id; Type: DEFAULT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.guides ALTER COLUMN id SET DEFAULT nextval('public.guides_id_seq'::regclass);
+
+
 --
 -- Name: images id; Type: DEFAULT; Schema: public; Owner: -
 --
@@ -177,6 +267,22 @@ ALTER TABLE ONLY public.loadouts ALTER COLUMN id SET DEFAULT nextval('public.loa
 ALTER TABLE ONLY public.users ALTER COLUMN id SET DEFAULT nextval('public.users_id_seq'::regclass);
 
 
+--
+-- Name: guide_revisions guide_revisions_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.guide_revisions
+    ADD CONSTRAINT guide_revisions_pkey PRIMARY KEY (id);
+
+
+--
+-- Name: guides guides_pkey; Type: CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.guides
+    ADD CONSTRAINT guides_pkey PRIMARY KEY (id);
+
+
 --
 -- Name: images images_pkey; Type: CONSTRAINT; Schema: public; Owner: -
 --
@@ -225,12 +331,72 @@ ALTER TABLE ONLY public.users
     ADD CONSTRAINT users_steam_id_key UNIQUE (steam_id);
 
 
+--
+-- Name: guides_search_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX guides_search_idx ON public.guides USING gin (to_tsvector('english'::regconfig, (((title)::text || ' '::text) || (body)::text)));
+
+
+--
+-- Name: loadouts_search_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX loadouts_search_idx ON public.loadouts USING gin (to_tsvector('simple'::regconfig, (((name)::text || ' '::text) || (data)::text)));
+
+
+--
+-- Name: loadouts_user_id_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX loadouts_user_id_idx ON public.loadouts USING btree (user_id);
+
+
+--
+-- Name: users_search_idx; Type: INDEX; Schema: public; Owner: -
+--
+
+CREATE INDEX users_search_idx ON public.users USING gin (to_tsvector('simple'::regconfig, (COALESCE(persona_name, ''::character varying))::text));
+
+
+--
+-- Name: guide_revisions guide_revisions_guide_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.guide_revisions
+    ADD CONSTRAINT guide_revisions_guide_id_fkey FOREIGN KEY (guide_id) REFERENCES public.guides(id);
+
+
+--
+-- Name: guide_revisions guide_revisions_reviewed_by_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.guide_revisions
+    ADD CONSTRAINT guide_revisions_reviewed_by_fkey FOREIGN KEY (reviewed_by) REFERENCES public.users(id);
+
+
+--
+-- Name: guide_revisions guide_revisions_user_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.guide_revisions
+    ADD CONSTRAINT guide_revisions_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id);
+
+
+--
+-- Name: guides guides_user_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
+--
+
+ALTER TABLE ONLY public.guides
+    ADD CONSTRAINT guides_user_id_fkey FOREIGN KEY (user_id) REFERENCES public.users(id);
+
+
 --
 -- Name: images images_loadout_id_fkey; Type: FK CONSTRAINT; Schema: public; Owner: -
 --
 
 ALTER TABLE ONLY public.images
-    ADD CONSTRAINT images_loadout_id_fkey FOREIGN KEY (loadout_id) REFERENCES public.loadouts(id);
+    ADD CONSTRAINT images_loadout_id_fkey FOREIGN KEY (loadout_id) REFERENCES public.loadouts(id) ON DELETE CASCADE;
 
 
 --
@@ -238,7 +404,7 @@ ALTER TABLE ONLY public.images
 --
 
 ALTER TABLE ONLY public.likes
-    ADD CONSTRAINT likes_loadout_id_fkey FOREIGN KEY (loadout_id) REFERENCES public.loadouts(id);
+    ADD CONSTRAINT likes_loadout_id_fkey FOREIGN KEY (loadout_id) REFERENCES public.loadouts(id) ON DELETE CASCADE;
 
 
 --
@@ -267,4 +433,12 @@ ALTER TABLE ONLY public.loadouts
 --
 
 INSERT INTO public.schema_migrations (version) VALUES
-    ('20190613202344');
+    ('20190613202344'),
+    ('20190705120000'),
+    ('20190712180000'),
+    ('20190720150000'),
+    ('20190727110000'),
+    ('20190805120000'),
+    ('20190806120000'),
+    ('20190807120000'),
+    ('20190807120100');
@@ -1,15 +1,39 @@
-use crate::models::User;
-use actix_web::HttpResponse;
+use crate::{
+    assets,
+    cache::{Cache, Caches},
+    db,
+    models::{LoadoutSingle, User},
+};
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    HttpMessage,
+    HttpRequest,
+    HttpResponse,
+};
 use askama::Template;
 use reqwest::r#async::Client;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub use crate::error::Error;
 
-pub type PgPool = l337::Pool<crate::db::ConnectionManager>;
+pub type PgPool = crate::db::Pool;
 pub type PgConn = crate::db::Connection;
 
+// Set by `after_write` to when the user's reads can go back to the replica, in
+// seconds since the epoch
+const READ_PRIMARY_COOKIE: &str = "read-primary";
+
+lazy_static::lazy_static! {
+    pub static ref SITE_URL: String = {
+        let url = std::env::var("SITE_URL").expect("SITE_URL is not set");
+        url.trim_end_matches('/').to_owned()
+    };
+}
+
 pub struct State {
     pool: PgPool,
+    replica: Option<db::Replica>,
+    cache: Caches,
     pub reqwest: reqwest::r#async::Client,
     pub redirector: steam_auth::Redirector,
 }
@@ -26,6 +50,11 @@ pub enum ActiveLink {
 pub struct TmplBase {
     pub user: Option<User>,
     pub active_link: ActiveLink,
+    // OpenGraph / Twitter card metadata, see the `meta` block in index.html
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+    pub canonical_url: Option<String>,
 }
 
 impl TmplBase {
@@ -33,14 +62,51 @@ impl TmplBase {
         Self {
             user,
             active_link: al,
+            title: None,
+            description: None,
+            image: None,
+            canonical_url: None,
         }
     }
+
+    pub fn with_title<S: Into<String>>(mut self, title: S) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    pub fn with_description<S: Into<String>>(mut self, description: S) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn with_image<S: Into<String>>(mut self, image: S) -> Self {
+        self.image = Some(image.into());
+        self
+    }
+
+    /// Sets the canonical URL from a path relative to `SITE_URL`, e.g `/loadouts/1`
+    pub fn with_canonical(mut self, path: &str) -> Self {
+        self.canonical_url = Some(format!("{}{}", *SITE_URL, path));
+        self
+    }
+
+    /// The url of a file in `static/`, e.g `base.asset("css/site.css")`
+    pub fn asset(&self, name: &str) -> String {
+        assets::url(name)
+    }
 }
 
 impl State {
-    pub fn new(pool: PgPool, redirector: steam_auth::Redirector) -> Self {
+    pub fn new(
+        pool: PgPool,
+        replica: Option<db::Replica>,
+        cache: Caches,
+        redirector: steam_auth::Redirector,
+    ) -> Self {
         Self {
             pool,
+            replica,
+            cache,
             reqwest: Client::new(),
             redirector,
         }
@@ -50,10 +116,121 @@ impl State {
         &self.pool
     }
 
+    // For pages which only read, and can put up with being a little out of
+    // date. This is the replica, unless it's down or too far behind, or `req`
+    // comes from someone who's just changed something (see `after_write`).
+    pub fn read_db(&self, req: &HttpRequest) -> &PgPool {
+        self.replica
+            .as_ref()
+            .filter(|replica| !reads_pinned(req, replica.staleness()))
+            .and_then(db::Replica::pool)
+            .unwrap_or(&self.pool)
+    }
+
+    // Like `read_db`, for reads which fill a cache. For a while after the cache
+    // is invalidated the replica may still have the old data, and caching it
+    // would keep it around for the whole TTL, so these go to the primary.
+    pub fn cache_db(&self, req: &HttpRequest) -> &PgPool {
+        match &self.replica {
+            Some(replica) if self.cache.invalidated_within(replica.staleness()) => &self.pool,
+            _ => self.read_db(req),
+        }
+    }
+
+    // Call on the response to a request which wrote to the database. The
+    // replica may not have the change yet, so the user's reads go to the
+    // primary until it must have, so that the page they're sent to next shows
+    // what they just did.
+    pub fn after_write(&self, mut res: HttpResponse) -> HttpResponse {
+        let replica = match &self.replica {
+            Some(replica) => replica,
+            None => return res,
+        };
+
+        let until = unix_time() + replica.staleness().as_secs() + 1;
+        let cookie = Cookie::build(READ_PRIMARY_COOKIE, until.to_string())
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .finish();
+
+        if let Err(e) = res.add_cookie(&cookie) {
+            error!("failed to set the {} cookie: {}", READ_PRIMARY_COOKIE, e);
+        }
+
+        res
+    }
+
+    pub fn record_metrics(&self) {
+        self.pool.record_metrics();
+        if let Some(replica) = &self.replica {
+            replica.record_metrics();
+        }
+    }
+
+    pub fn loadout_cache(&self) -> &Cache<(i32, Option<i32>), LoadoutSingle> {
+        &self.cache.loadouts
+    }
+
+    // Call after changing a loadout, its likes or its images
+    pub fn invalidate_loadout(&self, loadout_id: i32) {
+        self.cache.invalidate_loadout(loadout_id);
+    }
+
+    // A page rendered by `render_cached` under the same key, if it's still fresh
+    pub fn cached_page(&self, key: Option<&str>) -> Option<HttpResponse> {
+        key.and_then(|key| self.cache.pages.get(key)).map(html)
+    }
+
+    // Like `render`, but keeps the page for `cached_page` if there's a key.
+    // Only pages which are the same for every anonymous visitor should get
+    // one, so the key should be `None` for logged in users.
+    pub fn render_cached<T: Template>(
+        &self,
+        key: Option<String>,
+        ctx: T,
+    ) -> Result<HttpResponse, Error> {
+        let page = ctx.render().map_err(Error::Template)?;
+
+        if let Some(key) = key {
+            self.cache.pages.insert(key, page.clone());
+        }
+
+        Ok(html(page))
+    }
+
     pub fn render<T: Template>(ctx: T) -> Result<HttpResponse, Error> {
         match ctx.render() {
-            Ok(s) => Ok(HttpResponse::Ok().content_type("text/html").body(s)),
+            Ok(s) => Ok(html(s)),
             Err(e) => Err(Error::Template(e)),
         }
     }
 }
+
+fn html(page: String) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html").body(page)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+// Whether `req` has the cookie set by `after_write`, and it hasn't run out. Its
+// time can't be further away than `after_write` would set, so it can't be used
+// to keep reads on the primary for good.
+fn reads_pinned(req: &HttpRequest, staleness: Duration) -> bool {
+    let until = req
+        .cookie(READ_PRIMARY_COOKIE)
+        .and_then(|cookie| cookie.value().parse::<u64>().ok());
+
+    match until {
+        Some(until) => {
+            let now = unix_time();
+            now < until && until <= now + staleness.as_secs() + 1
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,279 @@
+// Fingerprinted static files.
+//
+// At startup every file in `static/` is read into memory, hashed and
+// compressed. Templates link to them with `base.asset("css/site.css")`, which
+// gives a url containing the hash of the file, e.g
+// `/static/css/site.3f9a0c1b2d4e5f60.css`. The content behind those urls never
+// changes, so they're served with a year long cache lifetime.
+//
+// Urls without a hash still work, for links stored outside of templates like
+// the icons in the game data, but they're read from disk on each request and
+// aren't cached by browsers. Debug builds use these in templates too, so that
+// changes show up without restarting the server.
+
+use actix_web::web::Bytes;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const STATIC_DIR: &str = "static";
+const URL_PREFIX: &str = "/static/";
+
+// Don't bother compressing anything where it would save less than this
+const MIN_COMPRESSION_SAVING: usize = 256;
+
+lazy_static::lazy_static! {
+    static ref ASSETS: io::Result<Assets> = Assets::load(STATIC_DIR);
+}
+
+pub fn dir() -> &'static Path {
+    Path::new(STATIC_DIR)
+}
+
+// Load and compress everything up front, rather than on the first request.
+// Called at startup, so that the server doesn't start without its static
+// files.
+pub fn init() -> Result<(), &'static io::Error> {
+    ASSETS.as_ref().map(|_| ())
+}
+
+// The url to link to a file in `static/` by, e.g `css/site.css`
+pub fn url(name: &str) -> String {
+    match assets().and_then(|assets| assets.fingerprinted.get(name)) {
+        Some(fingerprinted) if !cfg!(debug_assertions) => {
+            format!("{}{}", URL_PREFIX, fingerprinted)
+        }
+        Some(_) => format!("{}{}", URL_PREFIX, name),
+        None => {
+            warn!("linking to unknown static file '{}'", name);
+            format!("{}{}", URL_PREFIX, name)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+pub struct Asset {
+    pub content_type: &'static str,
+    pub data: Bytes,
+    pub gzip: Option<Bytes>,
+    pub brotli: Option<Bytes>,
+}
+
+pub enum Lookup<'a> {
+    // A fingerprinted url, whose content will never change
+    Immutable(&'a Asset),
+    // A plain url, read fresh from disk
+    Mutable {
+        path: PathBuf,
+        content_type: &'static str,
+    },
+    NotFound,
+}
+
+struct Assets {
+    // Keyed by fingerprinted name, e.g `css/site.3f9a0c1b2d4e5f60.css`
+    files: HashMap<String, Asset>,
+    // Plain name to fingerprinted name
+    fingerprinted: HashMap<String, String>,
+    dir: PathBuf,
+}
+
+pub fn lookup(name: &str) -> Lookup<'static> {
+    let assets = match assets() {
+        Some(assets) => assets,
+        None => return Lookup::NotFound,
+    };
+
+    if let Some(asset) = assets.files.get(name) {
+        return Lookup::Immutable(asset);
+    }
+
+    // Only serve files that were there at startup, which stops requests from
+    // reading anything outside of `static/`
+    if assets.fingerprinted.contains_key(name) {
+        return Lookup::Mutable {
+            path: assets.dir.join(name),
+            content_type: content_type(name),
+        };
+    }
+
+    Lookup::NotFound
+}
+
+// Only missing if `init` failed, in which case the server won't have started
+fn assets() -> Option<&'static Assets> {
+    ASSETS.as_ref().ok()
+}
+
+impl Asset {
+    fn new(name: &str, data: Vec<u8>) -> io::Result<Self> {
+        let content_type = content_type(name);
+        let (gzip, brotli) = if is_compressible(content_type) {
+            (smaller(&data, gzip(&data)?), smaller(&data, brotli(&data)?))
+        } else {
+            (None, None)
+        };
+
+        Ok(Self {
+            content_type,
+            data: Bytes::from(data),
+            gzip,
+            brotli,
+        })
+    }
+
+    // The best version of the file for an `Accept-Encoding` header
+    pub fn encoded(&self, accept_encoding: &str) -> (Encoding, Bytes) {
+        let accepts = |encoding: &str| {
+            accept_encoding.split(',').any(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let name = parts.next().unwrap_or_default();
+                let disabled = parts.any(|p| p == "q=0" || p == "q=0.0");
+
+                (name == encoding || name == "*") && !disabled
+            })
+        };
+
+        match (&self.brotli, &self.gzip) {
+            (Some(data), _) if accepts("br") => (Encoding::Brotli, data.clone()),
+            (_, Some(data)) if accepts("gzip") => (Encoding::Gzip, data.clone()),
+            _ => (Encoding::Identity, self.data.clone()),
+        }
+    }
+}
+
+impl Encoding {
+    pub fn header_value(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Identity => None,
+        }
+    }
+}
+
+impl Assets {
+    fn load<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        let mut paths = Vec::new();
+        find_files(dir, &mut paths)?;
+
+        let mut assets = Self {
+            files: HashMap::new(),
+            fingerprinted: HashMap::new(),
+            dir: dir.to_owned(),
+        };
+
+        for path in paths {
+            let name = path
+                .strip_prefix(dir)
+                .expect("static file outside of static dir")
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            let data = fs::read(&path)?;
+            let fingerprinted = fingerprint(&name, &data);
+
+            assets
+                .files
+                .insert(fingerprinted.clone(), Asset::new(&name, data)?);
+            assets.fingerprinted.insert(name, fingerprinted);
+        }
+
+        Ok(assets)
+    }
+}
+
+fn find_files(dir: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            find_files(&path, out)?;
+        } else {
+            out.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+// Insert a hash of the content before the extension, e.g `css/site.css` into
+// `css/site.3f9a0c1b2d4e5f60.css`
+fn fingerprint(name: &str, data: &[u8]) -> String {
+    let hash = openssl::sha::sha256(data)
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    let file_start = name.rfind('/').map_or(0, |i| i + 1);
+    match name[file_start..].rfind('.') {
+        Some(dot) => {
+            let dot = file_start + dot;
+            format!("{}.{}{}", &name[..dot], hash, &name[dot..])
+        }
+        None => format!("{}.{}", name, hash),
+    }
+}
+
+fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit('.').next().unwrap_or_default();
+
+    match extension.to_lowercase().as_str() {
+        "css" => "text/css; charset=utf-8",
+        "js" => "application/javascript; charset=utf-8",
+        "html" => "text/html; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+// Images and fonts are already compressed
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.starts_with("application/javascript")
+        || content_type.starts_with("application/json")
+        || content_type.starts_with("image/svg")
+}
+
+fn smaller(original: &[u8], compressed: Vec<u8>) -> Option<Bytes> {
+    if compressed.len() + MIN_COMPRESSION_SAVING <= original.len() {
+        Some(Bytes::from(compressed))
+    } else {
+        None
+    }
+}
+
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn brotli(data: &[u8]) -> io::Result<Vec<u8>> {
+    // Buffer size, quality (0-11) and window size (10-24)
+    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+    encoder.write_all(data)?;
+    Ok(encoder.into_inner())
+}
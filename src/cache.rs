@@ -0,0 +1,136 @@
+// Short-lived in-process caches for hot pages and queries. Entries are
+// dropped when the data behind them changes, but that only happens in this
+// process, so the TTLs also bound how stale another instance can get.
+//
+// Right after an invalidation the replica may not have the change yet, so
+// fills should come from the primary for a while, see `State::cache_db`.
+
+use crate::{metrics, models::LoadoutSingle};
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const PAGE_TTL: Duration = Duration::from_secs(30);
+const PAGE_CAPACITY: usize = 256;
+
+const LOADOUT_TTL: Duration = Duration::from_secs(60);
+const LOADOUT_CAPACITY: usize = 4096;
+
+// A map whose entries expire after `ttl`, holding at most `capacity` of them.
+// Clones share the same entries.
+#[derive(Clone)]
+pub struct Cache<K, V> {
+    name: &'static str,
+    ttl: Duration,
+    capacity: usize,
+    entries: Arc<Mutex<HashMap<K, (Instant, V)>>>,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Cache<K, V> {
+    pub fn new(name: &'static str, ttl: Duration, capacity: usize) -> Self {
+        Self {
+            name,
+            ttl,
+            capacity,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let mut entries = self.entries.lock().unwrap();
+
+        let value = match entries.get(key) {
+            Some((inserted, value)) if inserted.elapsed() < self.ttl => Some(value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        };
+
+        let result = if value.is_some() { "hit" } else { "miss" };
+        metrics::CACHE_LOOKUPS
+            .with_label_values(&[self.name, result])
+            .inc();
+
+        value
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let ttl = self.ttl;
+            entries.retain(|_, (inserted, _)| inserted.elapsed() < ttl);
+        }
+
+        // Still full of live entries, so make room by dropping the oldest
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (inserted, _))| *inserted)
+                .map(|(key, _)| key.clone());
+
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(key, (Instant::now(), value));
+    }
+
+    pub fn remove_where<F: Fn(&K) -> bool>(&self, f: F) {
+        self.entries.lock().unwrap().retain(|key, _| !f(key));
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+// The caches shared by every worker
+#[derive(Clone)]
+pub struct Caches {
+    // Rendered pages for anonymous visitors, who all see the same thing
+    pub pages: Cache<String, String>,
+    // Loadouts by id and the id of the user viewing them, as `has_liked`
+    // depends on who's looking
+    pub loadouts: Cache<(i32, Option<i32>), LoadoutSingle>,
+    // When anything was last invalidated
+    invalidated: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Default for Caches {
+    fn default() -> Self {
+        Self {
+            pages: Cache::new("pages", PAGE_TTL, PAGE_CAPACITY),
+            loadouts: Cache::new("loadouts", LOADOUT_TTL, LOADOUT_CAPACITY),
+            invalidated: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Caches {
+    // Forget anything showing a loadout, after it, its likes or its images
+    // change. Any listing page could include it, so they all go.
+    pub fn invalidate_loadout(&self, loadout_id: i32) {
+        *self.invalidated.lock().unwrap() = Some(Instant::now());
+        self.pages.clear();
+        self.loadouts.remove_where(|&(id, _)| id == loadout_id);
+    }
+
+    pub fn invalidated_within(&self, window: Duration) -> bool {
+        self.invalidated
+            .lock()
+            .unwrap()
+            .map_or(false, |invalidated| invalidated.elapsed() < window)
+    }
+}
@@ -0,0 +1,220 @@
+// Game data for weapons, armor and perks, loaded from the json files in
+// `data/catalog/`. Each subfolder is one version of the game data, named
+// after the game patch it describes.
+//
+// Items carry the ids the game uses for them in exported loadouts, see
+// `equipment`. Weapons have an equipment id, perks the bit they set in the
+// perks mask, and armor pieces the ids of every wearable of that tier.
+
+use crate::equipment;
+use std::{
+    env,
+    fmt,
+    fs,
+    path::{Path, PathBuf},
+};
+
+// Found relative to the crate rather than the working directory, so that the
+// server can be started from anywhere. `CATALOG_DIR` overrides it, e.g for a
+// binary that's been moved away from the source.
+const DEFAULT_CATALOG_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/data/catalog");
+const DEFAULT_ICON: &str = "/static/img/no-image.png";
+
+lazy_static::lazy_static! {
+    static ref CATALOGS: Result<Vec<Catalog>, CatalogError> = Catalog::load_all(dir());
+}
+
+pub fn dir() -> PathBuf {
+    env::var_os("CATALOG_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CATALOG_DIR))
+}
+
+// Load the game data. Called at startup, so that the server doesn't start
+// without it.
+pub fn init() -> Result<(), &'static CatalogError> {
+    CATALOGS.as_ref().map(|_| ())
+}
+
+// The most recent version of the game data
+pub fn current() -> &'static Catalog {
+    versions().last().expect("no game data versions loaded")
+}
+
+// The game data for a particular patch, if we have it
+pub fn version(version: &str) -> Option<&'static Catalog> {
+    versions().iter().find(|c| c.version == version)
+}
+
+// Every version of the game data, oldest first. Empty if it failed to load,
+// which `init` reports.
+pub fn versions() -> &'static [Catalog] {
+    match &*CATALOGS {
+        Ok(catalogs) => catalogs,
+        Err(_) => &[],
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum CatalogError {
+    #[fail(display = "io error: {}", _0)]
+    Io(std::io::Error),
+    #[fail(display = "invalid json in {}: {}", _0, _1)]
+    Json(String, serde_json::Error),
+    #[fail(display = "no versions found")]
+    NoVersions,
+}
+
+impl From<std::io::Error> for CatalogError {
+    fn from(e: std::io::Error) -> Self {
+        CatalogError::Io(e)
+    }
+}
+
+fn default_icon() -> String {
+    DEFAULT_ICON.to_owned()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Weapon {
+    pub id: i32,
+    pub name: String,
+    pub cost: u32,
+    #[serde(default)]
+    pub two_handed: bool,
+    #[serde(default)]
+    pub shield: bool,
+    pub slash_damage: u32,
+    pub stab_damage: u32,
+    pub speed_ms: u32,
+    pub length_cm: u32,
+    #[serde(default = "default_icon")]
+    pub icon: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArmorSlot {
+    Head,
+    Torso,
+    Legs,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArmorPiece {
+    pub slot: ArmorSlot,
+    pub tier: u8,
+    #[serde(default)]
+    pub ids: Vec<i32>,
+    pub name: String,
+    pub cost: u32,
+    pub protection: u32,
+    #[serde(default = "default_icon")]
+    pub icon: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Perk {
+    pub id: u32,
+    pub name: String,
+    pub cost: u32,
+    pub description: String,
+    #[serde(default = "default_icon")]
+    pub icon: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    pub version: String,
+    pub weapons: Vec<Weapon>,
+    pub armor: Vec<ArmorPiece>,
+    pub perks: Vec<Perk>,
+}
+
+impl Weapon {
+    // Used in urls, e.g `/catalog/weapons/executionerssword`
+    pub fn slug(&self) -> String {
+        equipment::normalize(&self.name)
+    }
+}
+
+impl Catalog {
+    // Load every version in `dir`, sorted by patch number
+    pub fn load_all<P: AsRef<Path>>(dir: P) -> Result<Vec<Self>, CatalogError> {
+        let mut versions = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect::<Vec<PathBuf>>();
+
+        if versions.is_empty() {
+            return Err(CatalogError::NoVersions);
+        }
+
+        versions.sort_by_key(|path| {
+            version_key(
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or_default(),
+            )
+        });
+        versions.into_iter().map(Self::load).collect()
+    }
+
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self, CatalogError> {
+        let dir = dir.as_ref();
+
+        Ok(Self {
+            version: dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+                .to_owned(),
+            weapons: read_json(&dir.join("weapons.json"))?,
+            armor: read_json(&dir.join("armor.json"))?,
+            perks: read_json(&dir.join("perks.json"))?,
+        })
+    }
+
+    pub fn weapon(&self, name: &str) -> Option<&Weapon> {
+        let name = equipment::normalize(name);
+        self.weapons
+            .iter()
+            .find(|w| equipment::normalize(&w.name) == name)
+    }
+
+    pub fn weapon_by_id(&self, id: i32) -> Option<&Weapon> {
+        self.weapons.iter().find(|w| w.id == id)
+    }
+
+    pub fn perk_by_id(&self, id: u32) -> Option<&Perk> {
+        self.perks.iter().find(|p| p.id == id)
+    }
+
+    // The armor piece a wearable in `slot` counts as
+    pub fn armor_by_id(&self, slot: ArmorSlot, id: i32) -> Option<&ArmorPiece> {
+        self.armor
+            .iter()
+            .find(|a| a.slot == slot && a.ids.contains(&id))
+    }
+}
+
+// Sort numeric patch names numerically, so that "15" < "100"
+pub fn version_key(version: &str) -> (u64, String) {
+    (version.parse().unwrap_or(0), version.to_owned())
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, CatalogError> {
+    let data = fs::read_to_string(path)?;
+    serde_json::from_str(&data).map_err(|e| CatalogError::Json(path.display().to_string(), e))
+}
+
+impl fmt::Display for ArmorSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArmorSlot::Head => write!(f, "Head"),
+            ArmorSlot::Torso => write!(f, "Torso"),
+            ArmorSlot::Legs => write!(f, "Legs"),
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use crate::{
+    catalog::{self, ArmorSlot, Catalog},
+    equipment::Equipment,
+};
+use std::{collections::HashSet, fmt};
+
+// Total number of points a loadout may spend across weapons, armor and perks
+pub const BUDGET: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemKind {
+    Weapon,
+    Armor,
+    Perk,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ItemCost {
+    pub kind: ItemKind,
+    pub name: String,
+    pub cost: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum Problem {
+    Unparseable(String),
+    UnknownWeapon(i32),
+    UnknownPerk(u32),
+    UnknownArmor(ArmorSlot, i32),
+    DuplicateItem(String),
+    MultipleShields,
+    TwoHandedWithShield { weapon: String, shield: String },
+    OverBudget { total: u32, budget: u32 },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CostReport {
+    pub items: Vec<ItemCost>,
+    pub total: u32,
+    pub budget: u32,
+    pub valid: bool,
+    pub problems: Vec<Problem>,
+}
+
+impl CostReport {
+    fn new(items: Vec<ItemCost>, mut problems: Vec<Problem>) -> Self {
+        let total = items.iter().map(|i| i.cost).sum();
+
+        if total > BUDGET {
+            problems.push(Problem::OverBudget {
+                total,
+                budget: BUDGET,
+            });
+        }
+
+        Self {
+            items,
+            total,
+            budget: BUDGET,
+            valid: problems.is_empty(),
+            problems,
+        }
+    }
+}
+
+// Parse and cost a loadout's `data` field against the current game data.
+// Parse errors are reported as a problem rather than an error, so that every
+// loadout has a report to show.
+pub fn evaluate(data: &str) -> CostReport {
+    evaluate_with(catalog::current(), data)
+}
+
+pub fn evaluate_with(catalog: &Catalog, data: &str) -> CostReport {
+    match Equipment::parse(data) {
+        Ok(equipment) => evaluate_equipment(catalog, &equipment),
+        Err(e) => CostReport::new(Vec::new(), vec![Problem::Unparseable(e.to_string())]),
+    }
+}
+
+pub fn evaluate_equipment(catalog: &Catalog, equipment: &Equipment) -> CostReport {
+    let mut items = Vec::new();
+    let mut problems = Vec::new();
+    // Repeated weapons are reported, and only costed the first time. Perks are
+    // bits in a mask, so they can't be repeated.
+    let mut seen = HashSet::new();
+
+    let mut two_handed = None;
+    let mut shields = Vec::new();
+
+    for &id in &equipment.weapons {
+        let weapon = catalog.weapon_by_id(id);
+
+        if !seen.insert(id) {
+            let name = weapon.map_or_else(|| id.to_string(), |w| w.name.clone());
+            problems.push(Problem::DuplicateItem(name));
+            continue;
+        }
+
+        match weapon {
+            Some(weapon) => {
+                if weapon.two_handed && two_handed.is_none() {
+                    two_handed = Some(&weapon.name);
+                }
+                if weapon.shield {
+                    shields.push(&weapon.name);
+                }
+
+                items.push(ItemCost {
+                    kind: ItemKind::Weapon,
+                    name: weapon.name.clone(),
+                    cost: weapon.cost,
+                });
+            }
+            None => problems.push(Problem::UnknownWeapon(id)),
+        }
+    }
+
+    if shields.len() > 1 {
+        problems.push(Problem::MultipleShields);
+    }
+
+    if let (Some(weapon), Some(shield)) = (two_handed, shields.first()) {
+        problems.push(Problem::TwoHandedWithShield {
+            weapon: weapon.clone(),
+            shield: (*shield).clone(),
+        });
+    }
+
+    let armor = equipment.armor;
+    for (slot, id) in &[
+        (ArmorSlot::Head, armor.head),
+        (ArmorSlot::Torso, armor.torso),
+        (ArmorSlot::Legs, armor.legs),
+    ] {
+        match catalog.armor_by_id(*slot, *id) {
+            Some(piece) => items.push(ItemCost {
+                kind: ItemKind::Armor,
+                name: format!("{} ({})", slot, piece.name),
+                cost: piece.cost,
+            }),
+            None => problems.push(Problem::UnknownArmor(*slot, *id)),
+        }
+    }
+
+    for &id in &equipment.perks {
+        match catalog.perk_by_id(id) {
+            Some(perk) => items.push(ItemCost {
+                kind: ItemKind::Perk,
+                name: perk.name.clone(),
+                cost: perk.cost,
+            }),
+            None => problems.push(Problem::UnknownPerk(id)),
+        }
+    }
+
+    CostReport::new(items, problems)
+}
+
+// How a loadout has fared since the patch it was created against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum PatchStatus {
+    // Created against the current patch
+    Current,
+    // Created against an older patch, but costs the same and is still valid
+    Unchanged,
+    // Still valid, but the total cost changed
+    CostChanged { old: u32, new: u32 },
+    // No longer valid under the current patch
+    Invalid,
+    // Created against a patch we have no game data for
+    UnknownPatch,
+}
+
+impl PatchStatus {
+    // Whether the loadout should be flagged as outdated
+    pub fn is_outdated(self) -> bool {
+        match self {
+            PatchStatus::CostChanged { .. } | PatchStatus::Invalid => true,
+            PatchStatus::Current | PatchStatus::Unchanged | PatchStatus::UnknownPatch => false,
+        }
+    }
+}
+
+pub fn patch_status(data: &str, game_version: &str) -> PatchStatus {
+    let current = catalog::current();
+    if current.version == game_version {
+        return PatchStatus::Current;
+    }
+
+    let original = match catalog::version(game_version) {
+        Some(original) => evaluate_with(original, data),
+        None => return PatchStatus::UnknownPatch,
+    };
+    let now = evaluate_with(current, data);
+
+    if original.valid && !now.valid {
+        PatchStatus::Invalid
+    } else if original.total != now.total {
+        PatchStatus::CostChanged {
+            old: original.total,
+            new: now.total,
+        }
+    } else {
+        PatchStatus::Unchanged
+    }
+}
+
+impl fmt::Display for ItemKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ItemKind::Weapon => write!(f, "Weapon"),
+            ItemKind::Armor => write!(f, "Armor"),
+            ItemKind::Perk => write!(f, "Perk"),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Unparseable(e) => write!(f, "Couldn't read loadout: {}", e),
+            Problem::UnknownWeapon(id) => write!(f, "Unknown weapon (id {})", id),
+            Problem::UnknownPerk(id) => write!(f, "Unknown perk (bit {})", id),
+            Problem::UnknownArmor(slot, id) => write!(f, "Unknown {} armor (id {})", slot, id),
+            Problem::DuplicateItem(i) => write!(f, "'{}' is equipped more than once", i),
+            Problem::MultipleShields => write!(f, "Only one shield can be equipped"),
+            Problem::TwoHandedWithShield { weapon, shield } => write!(
+                f,
+                "{} is two-handed and can't be used with {}",
+                weapon, shield
+            ),
+            Problem::OverBudget { total, budget } => {
+                write!(f, "Costs {} points, over the {} point limit", total, budget)
+            }
+        }
+    }
+}
+
+impl fmt::Display for PatchStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchStatus::Current | PatchStatus::Unchanged => write!(f, "Up to date"),
+            PatchStatus::CostChanged { old, new } => {
+                write!(f, "Cost changed from {} to {} points", old, new)
+            }
+            PatchStatus::Invalid => write!(f, "No longer valid"),
+            PatchStatus::UnknownPatch => write!(f, "Unknown patch"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        catalog::{ArmorPiece, Perk, Weapon},
+        equipment::Armor,
+    };
+
+    fn weapon(id: i32, name: &str, cost: u32, two_handed: bool, shield: bool) -> Weapon {
+        Weapon {
+            id,
+            name: name.to_owned(),
+            cost,
+            two_handed,
+            shield,
+            slash_damage: 0,
+            stab_damage: 0,
+            speed_ms: 0,
+            length_cm: 0,
+            icon: String::new(),
+        }
+    }
+
+    fn armor(slot: ArmorSlot, id: i32, cost: u32) -> ArmorPiece {
+        ArmorPiece {
+            slot,
+            tier: cost as u8,
+            ids: vec![id],
+            name: format!("Tier {}", cost),
+            cost,
+            protection: 0,
+            icon: String::new(),
+        }
+    }
+
+    fn catalog() -> Catalog {
+        Catalog {
+            version: "test".to_owned(),
+            weapons: vec![
+                weapon(1, "Arming Sword", 5, false, false),
+                weapon(2, "Zweihander", 11, true, false),
+                weapon(3, "Kite Shield", 2, false, true),
+                weapon(4, "Buckler", 1, false, true),
+            ],
+            armor: vec![
+                armor(ArmorSlot::Head, 0, 0),
+                armor(ArmorSlot::Head, 3, 3),
+                armor(ArmorSlot::Torso, 100, 0),
+                armor(ArmorSlot::Torso, 103, 3),
+                armor(ArmorSlot::Legs, 200, 0),
+            ],
+            perks: vec![Perk {
+                id: 0,
+                name: "Fury".to_owned(),
+                cost: 2,
+                description: String::new(),
+                icon: String::new(),
+            }],
+        }
+    }
+
+    fn equipment(weapons: Vec<i32>, head: i32, perks: Vec<u32>) -> Equipment {
+        Equipment {
+            weapons,
+            armor: Armor {
+                head,
+                torso: 100,
+                legs: 200,
+            },
+            perks,
+        }
+    }
+
+    #[test]
+    fn costs_a_valid_loadout() {
+        let report = evaluate_equipment(&catalog(), &equipment(vec![1, 3], 3, vec![0]));
+
+        assert_eq!(report.total, 5 + 2 + 3 + 2);
+        assert!(report.valid);
+        assert!(report.problems.is_empty());
+    }
+
+    #[test]
+    fn costs_duplicate_weapons_once() {
+        let report = evaluate_equipment(&catalog(), &equipment(vec![1, 1], 0, vec![]));
+
+        assert_eq!(report.total, 5);
+        assert_eq!(
+            report.problems,
+            vec![Problem::DuplicateItem("Arming Sword".to_owned())]
+        );
+        assert!(!report.valid);
+    }
+
+    #[test]
+    fn rejects_two_handed_weapons_with_a_shield() {
+        let report = evaluate_equipment(&catalog(), &equipment(vec![2, 4], 0, vec![]));
+
+        assert_eq!(
+            report.problems,
+            vec![Problem::TwoHandedWithShield {
+                weapon: "Zweihander".to_owned(),
+                shield: "Buckler".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn rejects_more_than_one_shield() {
+        let report = evaluate_equipment(&catalog(), &equipment(vec![3, 4], 0, vec![]));
+
+        assert_eq!(report.problems, vec![Problem::MultipleShields]);
+    }
+
+    #[test]
+    fn rejects_loadouts_over_budget() {
+        // 11 + 5 + 3 + 2 = 21 points
+        let report = evaluate_equipment(&catalog(), &equipment(vec![2, 1], 3, vec![0]));
+
+        assert_eq!(report.total, 21);
+        assert_eq!(
+            report.problems,
+            vec![Problem::OverBudget {
+                total: 21,
+                budget: BUDGET,
+            }]
+        );
+        assert!(!report.valid);
+    }
+
+    #[test]
+    fn reports_unknown_items() {
+        let report = evaluate_equipment(&catalog(), &equipment(vec![99], 7, vec![5]));
+
+        assert_eq!(
+            report.problems,
+            vec![
+                Problem::UnknownWeapon(99),
+                Problem::UnknownArmor(ArmorSlot::Head, 7),
+                Problem::UnknownPerk(5),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_unparseable_data() {
+        let report = evaluate_with(&catalog(), "not a loadout");
+
+        match report.problems.as_slice() {
+            [Problem::Unparseable(_)] => {}
+            other => panic!("expected an unparseable loadout, got {:?}", other),
+        }
+        assert!(!report.valid);
+    }
+}
@@ -1,23 +1,240 @@
 // Forked from https://github.com/OneSignal/L3-37/tree/master/l337-postgres
 // See licenses in vendor/l337
 
-use futures::{sync::oneshot, Async, Future, Stream};
+use futures::{future, sync::oneshot, Async, Future, IntoFuture, Stream};
 use tokio_postgres::{
-    error::Error,
+    error::{Error, SqlState},
     tls::{MakeTlsConnect, TlsConnect},
+    types::ToSql,
     Client,
+    Row,
     Socket,
 };
 
-use super::queries::Queries;
+use super::{
+    queries::{Access, Prepared, Queries},
+    timed::Timed,
+    FromRow,
+};
 
-use std::fmt;
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 pub struct Connection {
     pub client: Client,
     pub queries: Queries,
-    broken: bool,
-    receiver: oneshot::Receiver<bool>,
+    // Set when a query fails in a way that means the connection can't be
+    // reused, so that the pool replaces it with a freshly prepared one
+    broken: Arc<AtomicBool>,
+    // Set until a transaction is committed or rolled back. Connections given
+    // back to the pool with it set are closed rather than reused, which rolls
+    // the transaction back.
+    in_transaction: bool,
+    // Connected to the read replica
+    read_only: bool,
+    receiver: oneshot::Receiver<()>,
+}
+
+impl Connection {
+    // Run one of the prepared `Queries`, e.g
+    //
+    //   conn.query(|q| &q.applied_migrations, &[])
+    pub fn query(
+        &mut self,
+        query: fn(&Queries) -> &Prepared,
+        params: &[&dyn ToSql],
+    ) -> impl Stream<Item = Row, Error = Error> {
+        let prepared = query(&self.queries);
+        self.check_access(prepared);
+        let broken = self.broken.clone();
+        Timed::new(
+            prepared.name,
+            self.client.query(&prepared.statement, params),
+        )
+        .map_err(move |e| check_broken(&broken, e))
+    }
+
+    // Like `query`, but reads each row into the type the query was declared
+    // to return, e.g
+    //
+    //   conn.query_as(|q| &q.guide_single, &[&id]) // Stream<Item = Guide>
+    pub fn query_as<R: FromRow>(
+        &mut self,
+        query: fn(&Queries) -> &Prepared<R>,
+        params: &[&dyn ToSql],
+    ) -> impl Stream<Item = R, Error = Error> {
+        let prepared = query(&self.queries);
+        self.check_access(prepared);
+        let broken = self.broken.clone();
+        Timed::new(
+            prepared.name,
+            self.client.query(&prepared.statement, params),
+        )
+        .map_err(move |e| check_broken(&broken, e))
+        .map(|row| R::from_row(&row))
+    }
+
+    // Check that the connection is still usable
+    pub fn ping(&mut self) -> impl Future<Item = (), Error = Error> {
+        self.batch("")
+    }
+
+    // Run the future returned by `f` inside a transaction, which is committed
+    // once it succeeds. `f` is handed the connection and has to give it back
+    // along with its result, e.g
+    //
+    //   Connection::transaction(conn, move |mut conn| {
+    //       conn.execute(|q| &q.delete_loadout_images, &[&id])
+    //           .from_err()
+    //           .map(move |n| (conn, n))
+    //   })
+    //
+    // If anything fails the connection is dropped, and the transaction is
+    // rolled back before it goes back to the pool (see `Transaction`).
+    pub fn transaction<C, F, T, R>(
+        mut conn: C,
+        f: F,
+    ) -> impl Future<Item = (C, R), Error = T::Error>
+    where
+        C: DerefMut<Target = Connection> + 'static,
+        F: FnOnce(Transaction<C>) -> T,
+        T: IntoFuture<Item = (Transaction<C>, R)>,
+        T::Error: From<Error>,
+    {
+        conn.in_transaction = true;
+        let mut tx = Transaction { conn: Some(conn) };
+
+        tx.batch("BEGIN")
+            .from_err()
+            .and_then(move |_| f(tx))
+            .and_then(|(mut tx, result)| {
+                tx.batch("COMMIT").from_err().map(move |_| {
+                    let mut conn = tx.conn.take().unwrap();
+                    conn.in_transaction = false;
+                    (conn, result)
+                })
+            })
+    }
+
+    // Writes should never be routed to the replica, which would refuse them
+    fn check_access<R>(&self, prepared: &Prepared<R>) {
+        debug_assert!(
+            !self.read_only || prepared.access == Access::Read,
+            "write query `{}` sent to the read replica",
+            prepared.name
+        );
+    }
+
+    fn batch(&mut self, sql: &str) -> impl Future<Item = (), Error = Error> {
+        self.client.simple_query(sql).collect().map(|_| ())
+    }
+
+    // Like `query`, but returns the number of rows modified
+    pub fn execute(
+        &mut self,
+        query: fn(&Queries) -> &Prepared,
+        params: &[&dyn ToSql],
+    ) -> impl Future<Item = u64, Error = Error> {
+        let prepared = query(&self.queries);
+        self.check_access(prepared);
+        let broken = self.broken.clone();
+        Timed::new(
+            prepared.name,
+            self.client.execute(&prepared.statement, params),
+        )
+        .map_err(move |e| check_broken(&broken, e))
+    }
+}
+
+// A connection in the middle of a transaction. If it's dropped before being
+// committed, e.g because a query failed or the request was cancelled, the
+// transaction is rolled back in the background and then the connection goes
+// back to the pool. It's only closed if the rollback fails too.
+pub struct Transaction<C: DerefMut<Target = Connection> + 'static> {
+    conn: Option<C>,
+}
+
+impl<C: DerefMut<Target = Connection> + 'static> Deref for Transaction<C> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl<C: DerefMut<Target = Connection> + 'static> DerefMut for Transaction<C> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl<C: DerefMut<Target = Connection> + 'static> Drop for Transaction<C> {
+    fn drop(&mut self) {
+        let mut conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        actix_rt::spawn(conn.batch("ROLLBACK").then(move |res| {
+            match res {
+                Ok(()) => conn.in_transaction = false,
+                // Still marked as in a transaction, so the pool closes it
+                Err(e) => warn!("failed to roll back transaction: {}", e),
+            }
+            Ok(())
+        }));
+    }
+}
+
+// Flag the connection if the error means it's closed, or that its prepared
+// statements have gone away (e.g the server restarted behind a proxy)
+fn check_broken(broken: &AtomicBool, e: Error) -> Error {
+    if e.is_closed() || e.code() == Some(&SqlState::INVALID_SQL_STATEMENT_NAME) {
+        warn!("database connection broken: {}", e);
+        broken.store(true, Ordering::Relaxed);
+    }
+    e
+}
+
+// Errors from making or checking connections, for the pool to pass on
+#[derive(Debug, Fail)]
+pub enum ManagerError {
+    #[fail(display = "{}", _0)]
+    Postgres(Error),
+    #[fail(display = "timed out connecting to the database")]
+    TimedOut,
+    #[fail(display = "the connection pool is closing")]
+    Closing,
+}
+
+// Shared by a manager and the pool it's in, so that the pool can shut down
+// every connection the manager made
+#[derive(Clone, Default)]
+pub struct Closer {
+    closing: Arc<AtomicBool>,
+    // Connections whose background task is still running
+    open: Arc<AtomicUsize>,
+}
+
+impl Closer {
+    // Stop making connections, and drop each one as it's given back
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Relaxed)
+    }
+
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
 }
 
 pub struct PostgresConnectionManager<T>
@@ -26,6 +243,8 @@ where
 {
     config: tokio_postgres::Config,
     make_tls_connect: T,
+    read_only: bool,
+    closer: Closer,
 }
 
 impl<T> PostgresConnectionManager<T>
@@ -36,8 +255,21 @@ where
         Self {
             config,
             make_tls_connect,
+            read_only: false,
+            closer: Closer::default(),
         }
     }
+
+    pub fn closer(&self) -> Closer {
+        self.closer.clone()
+    }
+
+    // For connections to a read replica, where only `Access::Read` queries
+    // may be run
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
 }
 
 impl<T> l337::ManageConnection for PostgresConnectionManager<T>
@@ -48,31 +280,50 @@ where
     <T::TlsConnect as TlsConnect<Socket>>::Future: Send + Sync,
 {
     type Connection = Connection;
-    type Error = Error;
+    type Error = ManagerError;
 
     fn connect(
         &self,
     ) -> Box<Future<Item = Self::Connection, Error = l337::Error<Self::Error>> + 'static + Send>
     {
+        if self.closer.is_closing() {
+            return Box::new(future::err(l337::Error::External(ManagerError::Closing)));
+        }
+
+        let read_only = self.read_only;
+        let open = self.closer.open.clone();
+
         Box::new(
             self.config
                 .connect(self.make_tls_connect.clone())
-                .and_then(|(client, connection)| {
+                .and_then(move |(client, connection)| {
                     let (sender, receiver) = oneshot::channel();
-                    actix_rt::spawn(connection.map_err(|_| {
-                        sender
-                            .send(true)
-                            .unwrap_or_else(|e| panic!("failed to send shutdown notice: {}", e));
+                    open.fetch_add(1, Ordering::Relaxed);
+                    actix_rt::spawn(connection.then(move |res| {
+                        if let Err(e) = res {
+                            error!("database connection closed: {}", e);
+                        }
+                        open.fetch_sub(1, Ordering::Relaxed);
+                        // The receiver is gone if the pool already dropped this connection
+                        let _ = sender.send(());
+                        Ok(())
                     }));
+                    // Statements are prepared per connection, so every new
+                    // connection (including reconnects) gets its own set
                     Queries::prepare(client).map(move |(client, q)| (client, receiver, q))
                 })
-                .map(|(client, receiver, queries)| Connection {
-                    broken: false,
+                .map(move |(client, receiver, queries)| Connection {
+                    broken: Arc::new(AtomicBool::new(false)),
+                    in_transaction: false,
+                    read_only,
                     client,
                     receiver,
                     queries,
                 })
-                .map_err(l337::Error::External),
+                .map_err(|e| {
+                    error!("failed to connect to database: {}", e);
+                    l337::Error::External(ManagerError::Postgres(e))
+                }),
         )
     }
 
@@ -81,32 +332,29 @@ where
         mut conn: Self::Connection,
     ) -> Box<Future<Item = (), Error = l337::Error<Self::Error>>> {
         Box::new(
-            conn.client
-                .simple_query("")
-                .into_future()
-                .map(|_| ())
-                .map_err(|(e, _)| l337::Error::External(e)),
+            conn.ping()
+                .map_err(|e| l337::Error::External(ManagerError::Postgres(e))),
         )
     }
 
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
-        if conn.broken {
+        if conn.broken.load(Ordering::Relaxed) || conn.in_transaction || self.closer.is_closing() {
             return true;
         }
 
-        match conn.receiver.poll() {
-            Ok(Async::Ready(_)) => {
-                conn.broken = true;
-                true
-            }
+        // The connection task finished (or was dropped without saying so),
+        // either way the connection is gone
+        let broken = match conn.receiver.poll() {
             Ok(Async::NotReady) => false,
-            Err(err) => panic!("polling oneshot failed: {}", err),
-        }
+            Ok(Async::Ready(())) | Err(_) => true,
+        };
+
+        conn.broken.store(broken, Ordering::Relaxed);
+        broken
     }
 
     fn timed_out(&self) -> l337::Error<Self::Error> {
-        unimplemented!()
-        // Error::io(io::ErrorKind::TimedOut.into())
+        l337::Error::External(ManagerError::TimedOut)
     }
 }
 
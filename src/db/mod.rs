@@ -1,5 +1,15 @@
 mod manager;
+mod pool;
 mod queries;
+mod replica;
+#[macro_use]
+pub mod row;
+mod timed;
+pub mod tls;
 
-pub type ConnectionManager = manager::PostgresConnectionManager<tokio_postgres::NoTls>;
+pub type ConnectionManager = manager::PostgresConnectionManager<postgres_openssl::MakeTlsConnector>;
 pub type Connection = manager::Connection;
+pub use manager::ManagerError;
+pub use pool::Pool;
+pub use replica::Replica;
+pub use row::FromRow;
@@ -0,0 +1,138 @@
+use super::{manager::Closer, Connection, ConnectionManager};
+use crate::{app, metrics};
+use futures::{future, Future, Stream};
+use std::{
+    ops::DerefMut,
+    time::{Duration, Instant},
+};
+use tokio_timer::{Interval, Timeout};
+
+// How often to check whether connections have finished closing
+const CLOSE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// The l337 connection pool, wrapped so that we can keep track of how it's doing
+#[derive(Clone)]
+pub struct Pool {
+    inner: l337::Pool<ConnectionManager>,
+    closer: Closer,
+    // `primary` or `replica`, used to label metrics
+    name: &'static str,
+    // How long a request will wait for a connection before giving up
+    timeout: Duration,
+}
+
+impl Pool {
+    pub fn connect(
+        manager: ConnectionManager,
+        config: l337::Config,
+        name: &'static str,
+        timeout: Duration,
+    ) -> impl Future<Item = Self, Error = app::Error> {
+        let closer = manager.closer();
+
+        l337::Pool::new(manager, config)
+            .from_err()
+            .map(move |inner| Self {
+                inner,
+                closer,
+                name,
+                timeout,
+            })
+    }
+
+    pub fn connection(
+        &self,
+    ) -> impl Future<Item = impl DerefMut<Target = Connection>, Error = app::Error> {
+        let waiter = Waiter::new(self.name);
+
+        let pool_name = self.name;
+
+        Timeout::new(self.inner.connection(), self.timeout).then(move |res| {
+            waiter.finish();
+
+            res.map_err(|e| {
+                if e.is_elapsed() {
+                    warn!("timed out waiting for a {} database connection", pool_name);
+                    app::Error::PoolTimeout
+                } else if let Some(e) = e.into_inner() {
+                    e.into()
+                } else {
+                    error!("timer error while waiting for a database connection");
+                    app::Error::Internal
+                }
+            })
+        })
+    }
+
+    // Stop handing out connections and close the idle ones, waiting up to
+    // `timeout` for them to go. Connections in use are closed when they're
+    // given back, so this should be called once nothing is using the pool.
+    pub fn close(&self, timeout: Duration) -> impl Future<Item = (), Error = ()> {
+        self.closer.close();
+
+        // Connections are only dropped on the way back into the pool, so take
+        // each idle one out
+        let idle = (0..self.inner.idle_conns())
+            .map(|_| self.inner.connection().then(|_| Ok(())))
+            .collect::<Vec<_>>();
+
+        let closer = self.closer.clone();
+        let name = self.name;
+
+        let closed = future::join_all(idle).and_then(move |_| {
+            Interval::new(Instant::now(), CLOSE_POLL_INTERVAL)
+                .take_while(move |_| Ok(closer.open() > 0))
+                .for_each(|_| Ok(()))
+                .map_err(|e| error!("timer error while closing database connections: {}", e))
+        });
+
+        Timeout::new(closed, timeout).then(move |res| {
+            match res {
+                Ok(()) => info!("closed {} database connections", name),
+                Err(_) => warn!("gave up waiting for {} database connections to close", name),
+            }
+            Ok(())
+        })
+    }
+
+    // Update the pool gauges, called whenever metrics are scraped
+    pub fn record_metrics(&self) {
+        metrics::DB_POOL_CONNECTIONS
+            .with_label_values(&[self.name])
+            .set(self.inner.total_conns() as i64);
+        metrics::DB_POOL_IDLE
+            .with_label_values(&[self.name])
+            .set(self.inner.idle_conns() as i64);
+    }
+}
+
+// Counts towards the number of requests waiting for a connection for as long
+// as it's alive
+struct Waiter {
+    pool: &'static str,
+    start: Instant,
+}
+
+impl Waiter {
+    fn new(pool: &'static str) -> Self {
+        metrics::DB_POOL_WAITING.with_label_values(&[pool]).inc();
+        Self {
+            pool,
+            start: Instant::now(),
+        }
+    }
+
+    fn finish(self) {
+        metrics::DB_POOL_WAIT_DURATION
+            .with_label_values(&[self.pool])
+            .observe(metrics::seconds_since(self.start));
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        metrics::DB_POOL_WAITING
+            .with_label_values(&[self.pool])
+            .dec();
+    }
+}
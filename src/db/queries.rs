@@ -1,11 +1,63 @@
+use crate::models::{Guide, GuideRevision, Image, LoadoutMultiple, LoadoutSingle, User};
 use futures::Future;
-use tokio_postgres::{types::Type, Client};
+use std::marker::PhantomData;
+use tokio_postgres::{types::Type, Client, Row, Statement};
 
+// Whether a query only reads, and so can be sent to the read replica
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// A prepared statement, along with the name it was declared with below. The
+// name is used to label query timings. `T` is what each row is read into, see
+// `Connection::query_as`.
+pub struct Prepared<T = Row> {
+    pub name: &'static str,
+    pub access: Access,
+    pub statement: Statement,
+    row: PhantomData<fn() -> T>,
+}
+
+impl<T> Prepared<T> {
+    fn new(name: &'static str, access: Access, statement: Statement) -> Self {
+        Self {
+            name,
+            access,
+            statement,
+            row: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for Prepared<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.name, self.access, self.statement.clone())
+    }
+}
+
+// Each query is declared as `read|write name => sql, [param types]`,
+// optionally followed by `=> RowType` for queries read with
+// `Connection::query_as`. Anything which writes or takes locks is `write`.
 macro_rules! typed_queries {
-    ($($name:ident => $query:expr, $sql_types:expr;)*) => {
+    (@access read) => { Access::Read };
+    (@access write) => { Access::Write };
+
+    (@row) => { Row };
+    (@row $row:ty) => { $row };
+
+    (@check $name:ident, $statement:expr) => {};
+    (@check $name:ident, $statement:expr, $row:ty) => {
+        if let Err(e) = <$row as $crate::db::FromRow>::check_columns($statement.columns()) {
+            panic!("query `{}` can't be read as {}: {}", stringify!($name), stringify!($row), e);
+        }
+    };
+
+    ($($access:ident $name:ident => $query:expr, $sql_types:expr $(=> $row:ty)?;)*) => {
         #[derive(Builder)]
         pub struct Queries {
-            $(pub $name: tokio_postgres::Statement,)*
+            $(pub $name: Prepared<typed_queries!(@row $($row)?)>,)*
         }
 
         impl Queries {
@@ -22,8 +74,14 @@ macro_rules! typed_queries {
                     client
                         .prepare_typed($query, &$sql_types)
                         .map(move |statement| {
+                            typed_queries!(@check $name, statement $(, $row)?);
+
                             let mut b = builder.lock().unwrap();
-                            b.$name(statement);
+                            b.$name(Prepared::new(
+                                stringify!($name),
+                                typed_queries!(@access $access),
+                                statement,
+                            ));
                         })
                 })); )*
 
@@ -38,47 +96,226 @@ macro_rules! typed_queries {
 
 // https://docs.rs/tokio-postgres/0.4.0-rc.2/tokio_postgres/types/struct.Type.html
 typed_queries! {
-    get_image_by_id => "SELECT id, url, loadout_id, position, created_at FROM images WHERE loadout_id = $1 ORDER BY position ASC", [Type::INT4];
+    read applied_migrations => "SELECT version FROM schema_migrations", [];
 
-    loadout_single_with_user =>
-        "SELECT id, user_id, name, data, created_at, \
+    // How far behind the primary a replica is, in seconds. A replica which
+    // has replayed everything it received isn't behind, however long ago the
+    // last write was, but only if it's still receiving. If it isn't, this is
+    // NULL, as there's no telling what it's missing.
+    read replica_lag =>
+        "SELECT CASE \
+            WHEN NOT pg_is_in_recovery() THEN 0 \
+            WHEN NOT EXISTS (SELECT 1 FROM pg_stat_wal_receiver WHERE status = 'streaming') THEN NULL \
+            WHEN pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+            ELSE COALESCE(EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()), 0) \
+        END::float8",
+        [];
+
+    read get_image_by_id => "SELECT id, url, loadout_id, position, created_at FROM images WHERE loadout_id = $1 ORDER BY position ASC", [Type::INT4] => Image;
+
+    // `$1` is the logged in user, or NULL
+    read loadout_single =>
+        "SELECT id, user_id, name, data, game_version, created_at, \
         (SELECT COUNT(*) FROM likes WHERE likes.loadout_id = loadouts.id) as like_count, \
-        EXISTS (SELECT user_id FROM likes WHERE user_id = $1) AS has_liked \
+        EXISTS (SELECT user_id FROM likes WHERE user_id = $1 AND likes.loadout_id = loadouts.id) AS has_liked \
         FROM loadouts \
         WHERE loadouts.id = $2",
-        [Type::INT4, Type::INT4];
+        [Type::INT4, Type::INT4] => LoadoutSingle;
 
-    loadout_single_without_user =>
-        "SELECT id, user_id, name, data, created_at, \
-        (SELECT COUNT(*) FROM likes WHERE likes.loadout_id = loadouts.id) as like_count \
-        FROM loadouts \
-        WHERE loadouts.id = $1",
-        [Type::INT4];
+    // `$1` is the logged in user, or NULL
+    read loadout_multiple =>
+        "SELECT id, user_id, name, data, game_version, created_at, \
+        (SELECT COUNT(*) FROM likes WHERE likes.loadout_id = loadouts.id) as like_count, \
+        (SELECT steam_id FROM users WHERE users.id = loadouts.user_id) as user_steam_id, \
+        (SELECT url FROM images WHERE images.loadout_id = loadouts.id AND images.position = 0) as main_image_url, \
+        EXISTS (SELECT user_id FROM likes WHERE user_id = $1 AND likes.loadout_id = loadouts.id) AS has_liked FROM loadouts",
+        [Type::INT4] => LoadoutMultiple;
 
-    loadout_multiple_with_user =>
-        "SELECT id, user_id, name, data, created_at, \
+    // Like `loadout_multiple`, but only loadouts whose data matches the regex
+    // `$2`, most liked first
+    read loadouts_matching =>
+        "SELECT id, user_id, name, data, game_version, created_at, \
         (SELECT COUNT(*) FROM likes WHERE likes.loadout_id = loadouts.id) as like_count, \
         (SELECT steam_id FROM users WHERE users.id = loadouts.user_id) as user_steam_id, \
         (SELECT url FROM images WHERE images.loadout_id = loadouts.id AND images.position = 0) as main_image_url, \
-        EXISTS (SELECT user_id FROM likes WHERE user_id = $1) AS has_liked FROM loadouts",
-        [Type::INT4];
+        EXISTS (SELECT user_id FROM likes WHERE user_id = $1 AND likes.loadout_id = loadouts.id) AS has_liked FROM loadouts \
+        WHERE data ~* $2 \
+        ORDER BY like_count DESC",
+        [Type::INT4, Type::TEXT] => LoadoutMultiple;
 
-    loadout_multiple_without_user =>
-        "SELECT id, user_id, name, data, created_at, \
+    // Like `loadout_multiple`, but only the loadouts with the ids in `$2`
+    read loadouts_by_ids =>
+        "SELECT id, user_id, name, data, game_version, created_at, \
         (SELECT COUNT(*) FROM likes WHERE likes.loadout_id = loadouts.id) as like_count, \
         (SELECT steam_id FROM users WHERE users.id = loadouts.user_id) as user_steam_id, \
-        (SELECT url FROM images WHERE images.loadout_id = loadouts.id AND images.position = 0) as main_image_url FROM loadouts",
-        [];
+        (SELECT url FROM images WHERE images.loadout_id = loadouts.id AND images.position = 0) as main_image_url, \
+        EXISTS (SELECT user_id FROM likes WHERE user_id = $1 AND likes.loadout_id = loadouts.id) AS has_liked FROM loadouts \
+        WHERE id = ANY($2)",
+        [Type::INT4, Type::INT4_ARRAY] => LoadoutMultiple;
 
-    get_user_by_id => "SELECT id, steam_id FROM users WHERE steam_id = $1", [Type::INT4];
+    // Like `loadout_multiple`, but only `$2`'s loadouts
+    read loadouts_by_user =>
+        "SELECT id, user_id, name, data, game_version, created_at, \
+        (SELECT COUNT(*) FROM likes WHERE likes.loadout_id = loadouts.id) as like_count, \
+        (SELECT steam_id FROM users WHERE users.id = loadouts.user_id) as user_steam_id, \
+        (SELECT url FROM images WHERE images.loadout_id = loadouts.id AND images.position = 0) as main_image_url, \
+        EXISTS (SELECT user_id FROM likes WHERE user_id = $1 AND likes.loadout_id = loadouts.id) AS has_liked FROM loadouts \
+        WHERE user_id = $2",
+        [Type::INT4, Type::INT4] => LoadoutMultiple;
+
+    read get_user_by_id => "SELECT id, steam_id, is_moderator, persona_name FROM users WHERE steam_id = $1", [Type::INT8] => User;
 
-    post_login_insert_user => "INSERT INTO users (steam_id) VALUES ($1) ON CONFLICT DO NOTHING", [Type::INT8];
+    // Whether the user with steam id `$1` needs their persona name fetched,
+    // because they're new, or the one we have is missing or a day old
+    read user_persona_name_stale =>
+        "SELECT NOT EXISTS (SELECT 1 FROM users WHERE steam_id = $1 \
+            AND persona_name IS NOT NULL \
+            AND persona_name_updated_at > CURRENT_TIMESTAMP - interval '1 day')",
+        [Type::INT8];
 
-    create_loadout =>
-        "INSERT INTO loadouts (user_id, name, data, created_at) VALUES ($1, $2, $3, DEFAULT) RETURNING id",
-        [Type::INT4, Type::VARCHAR, Type::VARCHAR, Type::TIMESTAMP];
+    // A NULL persona name leaves the one we have, and when it was fetched
+    write post_login_insert_user =>
+        "INSERT INTO users (steam_id, persona_name, persona_name_updated_at) \
+        VALUES ($1, $2, CASE WHEN $2 IS NULL THEN NULL ELSE CURRENT_TIMESTAMP END) \
+        ON CONFLICT (steam_id) DO UPDATE SET \
+        persona_name = COALESCE(EXCLUDED.persona_name, users.persona_name), \
+        persona_name_updated_at = COALESCE(EXCLUDED.persona_name_updated_at, users.persona_name_updated_at)",
+        [Type::INT8, Type::VARCHAR];
 
-    create_image =>
+    write create_loadout =>
+        "INSERT INTO loadouts (user_id, name, data, game_version, created_at) VALUES ($1, $2, $3, $4, DEFAULT) RETURNING id",
+        [Type::INT4, Type::VARCHAR, Type::VARCHAR, Type::VARCHAR];
+
+    write create_image =>
         "INSERT INTO images (url, loadout_id, position) VALUES ($1, $2, $3)",
         [Type::VARCHAR, Type::INT4, Type::INT4];
+
+    // Locks the loadout until the end of the transaction
+    write lock_loadout => "SELECT user_id FROM loadouts WHERE id = $1 FOR UPDATE", [Type::INT4];
+
+    write update_loadout =>
+        "UPDATE loadouts SET name = $1, data = $2, game_version = $3 WHERE id = $4",
+        [Type::VARCHAR, Type::VARCHAR, Type::VARCHAR, Type::INT4];
+
+    // Its images and likes go with it
+    write delete_loadout => "DELETE FROM loadouts WHERE id = $1", [Type::INT4];
+
+    write delete_loadout_images => "DELETE FROM images WHERE loadout_id = $1", [Type::INT4];
+
+    write add_like => "INSERT INTO likes (user_id, loadout_id) VALUES ($1, $2)", [Type::INT4, Type::INT4];
+
+    write remove_like =>
+        "DELETE FROM likes WHERE user_id = $1 AND loadout_id = $2",
+        [Type::INT4, Type::INT4];
+
+    read guide_single =>
+        "SELECT id, user_id, title, body, published, created_at, updated_at, \
+        (SELECT steam_id FROM users WHERE users.id = guides.user_id) as author_steam_id \
+        FROM guides \
+        WHERE guides.id = $1",
+        [Type::INT4] => Guide;
+
+    read guide_multiple_published =>
+        "SELECT id, user_id, title, body, published, created_at, updated_at, \
+        (SELECT steam_id FROM users WHERE users.id = guides.user_id) as author_steam_id \
+        FROM guides \
+        WHERE published \
+        ORDER BY updated_at DESC",
+        [] => Guide;
+
+    read guide_multiple_by_user =>
+        "SELECT id, user_id, title, body, published, created_at, updated_at, \
+        (SELECT steam_id FROM users WHERE users.id = guides.user_id) as author_steam_id \
+        FROM guides \
+        WHERE user_id = $1 \
+        ORDER BY updated_at DESC",
+        [Type::INT4] => Guide;
+
+    // Every change to a guide is recorded as a revision, so the first one is
+    // created along with the guide
+    write create_guide =>
+        "WITH g AS (\
+            INSERT INTO guides (user_id, title, body, published) VALUES ($1, $2, $3, $4) \
+            RETURNING id, user_id, title, body) \
+        INSERT INTO guide_revisions (guide_id, user_id, title, body, status) \
+        SELECT id, user_id, title, body, 'accepted' FROM g RETURNING guide_id",
+        [Type::INT4, Type::VARCHAR, Type::VARCHAR, Type::BOOL];
+
+    write update_guide =>
+        "WITH g AS (\
+            UPDATE guides SET title = $1, body = $2, published = $3, updated_at = CURRENT_TIMESTAMP \
+            WHERE id = $4 AND user_id = $5 \
+            RETURNING id, user_id, title, body) \
+        INSERT INTO guide_revisions (guide_id, user_id, title, body, status) \
+        SELECT id, user_id, title, body, 'accepted' FROM g",
+        [Type::VARCHAR, Type::VARCHAR, Type::BOOL, Type::INT4, Type::INT4];
+
+    read guide_revisions =>
+        "SELECT id, guide_id, user_id, title, body, status, created_at, \
+        (SELECT steam_id FROM users WHERE users.id = guide_revisions.user_id) as editor_steam_id \
+        FROM guide_revisions \
+        WHERE guide_id = $1 \
+        ORDER BY id ASC",
+        [Type::INT4] => GuideRevision;
+
+    // Suggestions can only be made on published guides
+    write suggest_guide_revision =>
+        "INSERT INTO guide_revisions (guide_id, user_id, title, body, status) \
+        SELECT id, $2, $3, $4, 'pending' FROM guides WHERE id = $1 AND published",
+        [Type::INT4, Type::INT4, Type::VARCHAR, Type::VARCHAR];
+
+    write accept_guide_revision =>
+        "WITH r AS (\
+            UPDATE guide_revisions SET status = 'accepted', reviewed_by = $3, reviewed_at = CURRENT_TIMESTAMP \
+            WHERE id = $1 AND guide_id = $2 AND status = 'pending' \
+            RETURNING guide_id, title, body) \
+        UPDATE guides SET title = r.title, body = r.body, updated_at = CURRENT_TIMESTAMP \
+        FROM r WHERE guides.id = r.guide_id",
+        [Type::INT4, Type::INT4, Type::INT4];
+
+    write reject_guide_revision =>
+        "UPDATE guide_revisions SET status = 'rejected', reviewed_by = $3, reviewed_at = CURRENT_TIMESTAMP \
+        WHERE id = $1 AND guide_id = $2 AND status = 'pending'",
+        [Type::INT4, Type::INT4, Type::INT4];
+
+    // Search queries take a tsquery, a result limit and the options for
+    // `ts_headline`, see src/search.rs
+    read search_loadouts =>
+        "SELECT id, name, \
+        (SELECT steam_id FROM users WHERE users.id = loadouts.user_id) as user_steam_id, \
+        ts_headline('simple', name || ' ' || data, q, $3) \
+        FROM loadouts, to_tsquery('simple', $1) q \
+        WHERE to_tsvector('simple', name || ' ' || data) @@ q \
+        ORDER BY ts_rank(to_tsvector('simple', name || ' ' || data), q) DESC \
+        LIMIT $2",
+        [Type::TEXT, Type::INT8, Type::TEXT];
+
+    read search_users =>
+        "SELECT steam_id, persona_name, ts_headline('simple', persona_name, q, $3) \
+        FROM users, to_tsquery('simple', $1) q \
+        WHERE to_tsvector('simple', COALESCE(persona_name, '')) @@ q \
+        ORDER BY ts_rank(to_tsvector('simple', COALESCE(persona_name, '')), q) DESC \
+        LIMIT $2",
+        [Type::TEXT, Type::INT8, Type::TEXT];
+
+    // Guides are compiled into the binary, so their titles and text are
+    // passed in as arrays. Returns the 1-based index of each matching guide.
+    read search_guides =>
+        "SELECT g.idx, ts_headline('english', g.body, q, $3) \
+        FROM unnest($4::text[], $5::text[]) WITH ORDINALITY AS g(title, body, idx), \
+        to_tsquery('english', $1) q \
+        WHERE to_tsvector('english', g.title || ' ' || g.body) @@ q \
+        ORDER BY ts_rank(setweight(to_tsvector('english', g.title), 'A') || to_tsvector('english', g.body), q) DESC \
+        LIMIT $2",
+        [Type::TEXT, Type::INT8, Type::TEXT, Type::TEXT_ARRAY, Type::TEXT_ARRAY];
+
+    // Community guides, which unlike the ones above live in the database.
+    // Only published ones are found, as drafts are private to their author.
+    read search_community_guides =>
+        "SELECT id, title, ts_headline('english', body, q, $3) \
+        FROM guides, to_tsquery('english', $1) q \
+        WHERE published AND to_tsvector('english', title || ' ' || body) @@ q \
+        ORDER BY ts_rank(setweight(to_tsvector('english', title), 'A') || to_tsvector('english', body), q) DESC \
+        LIMIT $2",
+        [Type::TEXT, Type::INT8, Type::TEXT];
 }
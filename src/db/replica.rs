@@ -0,0 +1,122 @@
+use super::Pool;
+use crate::{app, metrics, shutdown};
+use futures::{Future, Stream};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio_timer::Interval;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// A read replica, which is only used while it's reachable and keeping up with
+// the primary. It's checked in the background, so that picking a pool for a
+// request doesn't have to wait on anything.
+#[derive(Clone)]
+pub struct Replica {
+    pool: Pool,
+    healthy: Arc<AtomicBool>,
+    max_lag: Duration,
+}
+
+impl Replica {
+    // Starts checking on the replica, which isn't used until the first check
+    // passes. The pool can be created before the replica is reachable, as it's
+    // only used once it is.
+    pub fn new(pool: Pool, max_lag: Duration) -> Self {
+        let replica = Self {
+            pool,
+            healthy: Arc::new(AtomicBool::new(false)),
+            max_lag,
+        };
+
+        replica.monitor();
+        replica
+    }
+
+    // How far behind the primary a read from the replica can be: it can fall
+    // up to `max_lag` behind, and then further until the next check notices
+    pub fn staleness(&self) -> Duration {
+        self.max_lag + CHECK_INTERVAL
+    }
+
+    // The replica's pool, if reads should be sent to it
+    pub fn pool(&self) -> Option<&Pool> {
+        if self.healthy.load(Ordering::Relaxed) {
+            Some(&self.pool)
+        } else {
+            None
+        }
+    }
+
+    pub fn record_metrics(&self) {
+        self.pool.record_metrics();
+    }
+
+    pub fn close(&self, timeout: Duration) -> impl Future<Item = (), Error = ()> {
+        self.pool.close(timeout)
+    }
+
+    fn monitor(&self) {
+        let pool = self.pool.clone();
+        let healthy = self.healthy.clone();
+        let max_lag = self.max_lag.as_secs() as f64 + f64::from(self.max_lag.subsec_millis()) / 1e3;
+
+        actix_rt::spawn(
+            Interval::new(Instant::now(), CHECK_INTERVAL)
+                .map_err(|e| error!("replica check timer failed: {}", e))
+                // Leave the pool alone while it's being closed
+                .take_while(|_| Ok(!shutdown::in_progress()))
+                .for_each(move |_| {
+                    let healthy = healthy.clone();
+
+                    lag(&pool).then(move |lag| {
+                        let ok = match lag {
+                            Ok(Some(lag)) => {
+                                metrics::DB_REPLICA_LAG.set(lag);
+                                lag <= max_lag
+                            }
+                            Ok(None) => {
+                                warn!("the read replica isn't streaming from the primary");
+                                false
+                            }
+                            Err(e) => {
+                                warn!("failed to check the read replica: {}", e);
+                                false
+                            }
+                        };
+
+                        if healthy.swap(ok, Ordering::Relaxed) != ok {
+                            if ok {
+                                info!("sending reads to the replica");
+                            } else {
+                                warn!(
+                                    "replica unavailable or lagging, sending reads to the primary"
+                                );
+                            }
+                        }
+                        metrics::DB_REPLICA_HEALTHY.set(ok as i64);
+
+                        Ok(())
+                    })
+                }),
+        );
+    }
+}
+
+// Seconds behind the primary, or `None` if the replica has lost its connection
+// to it, in which case it can't tell how far behind it is
+fn lag(pool: &Pool) -> impl Future<Item = Option<f64>, Error = app::Error> {
+    pool.connection().and_then(|mut conn| {
+        conn.query(|q| &q.replica_lag, &[])
+            .into_future()
+            .map_err(|(e, _)| app::Error::from(e))
+            .and_then(|(row, _)| {
+                row.map(|row| row.get(0))
+                    .ok_or(app::Error::DbNothingReturned)
+            })
+    })
+}
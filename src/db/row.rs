@@ -0,0 +1,71 @@
+use tokio_postgres::{types::FromSql, Column, Row};
+
+// A type which can be read from a row, with each field taken from the column
+// of the same name. Queries in `typed_queries!` which are declared to return
+// one are checked against it when they're prepared, so a missing or mistyped
+// column fails on startup rather than on first use.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Self;
+
+    fn check_columns(columns: &[Column]) -> Result<(), String>;
+}
+
+// Declare a struct along with its `FromRow` implementation, e.g
+//
+//   from_row! {
+//       pub struct Image {
+//           pub id: i32,
+//           pub url: String,
+//       }
+//   }
+macro_rules! from_row {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field_vis:vis $field:ident: $ty:ty,)*
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$field_meta])* $field_vis $field: $ty,)*
+        }
+
+        impl $crate::db::FromRow for $name {
+            fn from_row(row: &tokio_postgres::Row) -> Self {
+                Self {
+                    $($field: row.get(stringify!($field)),)*
+                }
+            }
+
+            fn check_columns(columns: &[tokio_postgres::Column]) -> Result<(), String> {
+                $($crate::db::row::check_column::<$ty>(
+                    columns,
+                    stringify!($field),
+                    stringify!($ty),
+                )?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+pub fn check_column<T>(columns: &[Column], name: &str, rust_type: &str) -> Result<(), String>
+where
+    T: for<'a> FromSql<'a>,
+{
+    let column = columns
+        .iter()
+        .find(|c| c.name() == name)
+        .ok_or_else(|| format!("missing column `{}`", name))?;
+
+    if T::accepts(column.type_()) {
+        Ok(())
+    } else {
+        Err(format!(
+            "column `{}` is {}, which can't be read as {}",
+            name,
+            column.type_(),
+            rust_type
+        ))
+    }
+}
@@ -0,0 +1,67 @@
+use crate::metrics;
+use futures::{Async, Future, Poll, Stream};
+use std::time::Instant;
+
+// Wraps a query's future or stream to record how long it took, labelled with
+// the statement's name. The time is recorded when the query finishes or
+// fails, or when it's dropped, e.g after taking the first row of a stream.
+pub struct Timed<T> {
+    inner: T,
+    name: &'static str,
+    start: Instant,
+    done: bool,
+}
+
+impl<T> Timed<T> {
+    pub fn new(name: &'static str, inner: T) -> Self {
+        Self {
+            inner,
+            name,
+            start: Instant::now(),
+            done: false,
+        }
+    }
+
+    fn finish(&mut self) {
+        if !self.done {
+            self.done = true;
+            metrics::DB_QUERY_DURATION
+                .with_label_values(&[self.name])
+                .observe(metrics::seconds_since(self.start));
+        }
+    }
+}
+
+impl<T: Future> Future for Timed<T> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let poll = self.inner.poll();
+        match poll {
+            Ok(Async::NotReady) => {}
+            _ => self.finish(),
+        }
+        poll
+    }
+}
+
+impl<T: Stream> Stream for Timed<T> {
+    type Item = T::Item;
+    type Error = T::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let poll = self.inner.poll();
+        match poll {
+            Ok(Async::Ready(None)) | Err(_) => self.finish(),
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl<T> Drop for Timed<T> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
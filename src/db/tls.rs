@@ -0,0 +1,217 @@
+// TLS for database connections. The mode is taken from the `sslmode` parameter
+// of `DATABASE_URL`, or `DATABASE_SSLMODE` if set, with the same meanings as
+// libpq:
+//
+//   disable     - never use TLS
+//   prefer      - use TLS if the server supports it, without verifying it
+//   require     - always use TLS, verifying the certificate only if a CA bundle is given
+//   verify-full - always use TLS, verifying the certificate and hostname
+//
+// A CA bundle can be given with `sslrootcert` (or `DATABASE_SSLROOTCERT`),
+// otherwise the system's trusted certificates are used.
+
+use openssl::{
+    error::ErrorStack,
+    ssl::{SslConnector, SslMethod, SslVerifyMode},
+};
+use postgres_openssl::MakeTlsConnector;
+use std::{
+    env,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tokio_postgres::config::{Config, SslMode};
+use url::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyFull,
+}
+
+#[derive(Debug, Fail)]
+pub enum TlsError {
+    #[fail(display = "invalid sslmode '{}'", _0)]
+    InvalidMode(String),
+    #[fail(display = "invalid database url: {}", _0)]
+    Url(url::ParseError),
+    #[fail(display = "invalid database config: {}", _0)]
+    Config(tokio_postgres::Error),
+    #[fail(display = "failed to set up tls: {}", _0)]
+    Ssl(ErrorStack),
+}
+
+impl TlsMode {
+    // tokio-postgres doesn't know about verification, that's up to the connector
+    fn ssl_mode(self) -> SslMode {
+        match self {
+            TlsMode::Disable => SslMode::Disable,
+            TlsMode::Prefer => SslMode::Prefer,
+            TlsMode::Require | TlsMode::VerifyFull => SslMode::Require,
+        }
+    }
+}
+
+impl FromStr for TlsMode {
+    type Err = TlsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disable" => Ok(TlsMode::Disable),
+            "prefer" => Ok(TlsMode::Prefer),
+            "require" => Ok(TlsMode::Require),
+            "verify-full" => Ok(TlsMode::VerifyFull),
+            _ => Err(TlsError::InvalidMode(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for TlsMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsMode::Disable => write!(f, "disable"),
+            TlsMode::Prefer => write!(f, "prefer"),
+            TlsMode::Require => write!(f, "require"),
+            TlsMode::VerifyFull => write!(f, "verify-full"),
+        }
+    }
+}
+
+// Parse a database url into a connection config and a matching tls connector
+pub fn configure(db_url: &str) -> Result<(Config, MakeTlsConnector), TlsError> {
+    let (db_url, mut mode, mut ca_file) = strip_tls_params(db_url)?;
+
+    if let Ok(env_mode) = env::var("DATABASE_SSLMODE") {
+        mode = Some(env_mode.parse()?);
+    }
+    if let Ok(env_ca_file) = env::var("DATABASE_SSLROOTCERT") {
+        ca_file = Some(env_ca_file.into());
+    }
+
+    // Same default as libpq
+    let mode = mode.unwrap_or(TlsMode::Prefer);
+
+    let mut config = db_url.parse::<Config>().map_err(TlsError::Config)?;
+    config.ssl_mode(mode.ssl_mode());
+
+    let connector =
+        connector(mode, ca_file.as_ref().map(PathBuf::as_path)).map_err(TlsError::Ssl)?;
+
+    info!(
+        "database tls mode: {}{}",
+        mode,
+        ca_file
+            .map(|f| format!(" (ca bundle {})", f.display()))
+            .unwrap_or_default()
+    );
+
+    Ok((config, connector))
+}
+
+// tokio-postgres rejects `verify-full` and `sslrootcert`, so take them out of
+// the url before it sees it
+fn strip_tls_params(db_url: &str) -> Result<(String, Option<TlsMode>, Option<PathBuf>), TlsError> {
+    // Key-value style configs are passed through as is
+    if !db_url.starts_with("postgres://") && !db_url.starts_with("postgresql://") {
+        return Ok((db_url.to_owned(), None, None));
+    }
+
+    let mut url = Url::parse(db_url).map_err(TlsError::Url)?;
+    let mut mode = None;
+    let mut ca_file = None;
+    let mut rest = Vec::new();
+
+    for (key, value) in url.query_pairs().into_owned() {
+        match key.as_str() {
+            "sslmode" => mode = Some(value.parse()?),
+            "sslrootcert" => ca_file = Some(value.into()),
+            _ => rest.push((key, value)),
+        }
+    }
+
+    if rest.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(rest);
+    }
+
+    Ok((url.into_string(), mode, ca_file))
+}
+
+fn connector(mode: TlsMode, ca_file: Option<&Path>) -> Result<MakeTlsConnector, ErrorStack> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+
+    if let Some(ca_file) = ca_file {
+        builder.set_ca_file(ca_file)?;
+    }
+
+    let verify_cert = match mode {
+        TlsMode::VerifyFull => true,
+        TlsMode::Require => ca_file.is_some(),
+        TlsMode::Disable | TlsMode::Prefer => false,
+    };
+    if !verify_cert {
+        builder.set_verify(SslVerifyMode::NONE);
+    }
+
+    let mut connector = MakeTlsConnector::new(builder.build());
+    if mode != TlsMode::VerifyFull {
+        connector.set_callback(|config, _| {
+            config.set_verify_hostname(false);
+            Ok(())
+        });
+    }
+
+    Ok(connector)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_tls_params_and_keeps_the_rest() {
+        let (url, mode, ca_file) = strip_tls_params(
+            "postgres://user:pass@db:5432/mordhub?sslmode=verify-full&application_name=web&sslrootcert=/etc/ca.pem",
+        )
+        .unwrap();
+
+        assert_eq!(
+            url,
+            "postgres://user:pass@db:5432/mordhub?application_name=web"
+        );
+        assert_eq!(mode, Some(TlsMode::VerifyFull));
+        assert_eq!(ca_file, Some(PathBuf::from("/etc/ca.pem")));
+    }
+
+    #[test]
+    fn drops_an_empty_query() {
+        let (url, mode, ca_file) =
+            strip_tls_params("postgresql://db/mordhub?sslmode=disable").unwrap();
+
+        assert_eq!(url, "postgresql://db/mordhub");
+        assert_eq!(mode, Some(TlsMode::Disable));
+        assert_eq!(ca_file, None);
+    }
+
+    #[test]
+    fn passes_key_value_configs_through() {
+        let config = "host=db user=postgres sslmode=require";
+        let (url, mode, ca_file) = strip_tls_params(config).unwrap();
+
+        assert_eq!(url, config);
+        assert_eq!(mode, None);
+        assert_eq!(ca_file, None);
+    }
+
+    #[test]
+    fn rejects_unknown_modes() {
+        match strip_tls_params("postgres://db/mordhub?sslmode=sometimes") {
+            Err(TlsError::InvalidMode(mode)) => assert_eq!(mode, "sometimes"),
+            _ => panic!("expected an invalid sslmode error"),
+        }
+    }
+}
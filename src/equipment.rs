@@ -0,0 +1,181 @@
+// The parts of a loadout that cost points, read from its `data` field.
+//
+// Loadouts are stored as Mordhau exports them, a `CharacterProfiles=(...)`
+// entry from its config files, and are parsed with `mordhau_loadout_parser`.
+// Items are given by the ids the game uses, which the catalog maps to names
+// and costs.
+
+use mordhau_loadout_parser::{
+    error::Error,
+    loadout::Loadout,
+    parser::{self, KVList, Value},
+    wearable::WearableSlot,
+};
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Equipment {
+    // Equipment ids, in the order they're equipped
+    pub weapons: Vec<i32>,
+    pub armor: Armor,
+    // Bits set in the perks mask
+    pub perks: Vec<u32>,
+}
+
+// The wearables which decide the armor tier of each slot
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Armor {
+    pub head: i32,
+    pub torso: i32,
+    pub legs: i32,
+}
+
+#[derive(Debug, Clone, Fail)]
+pub enum ParseError {
+    #[fail(display = "{}", _0)]
+    Loadout(Error),
+    #[fail(display = "no {} wearable", _0)]
+    MissingWearable(&'static str),
+}
+
+impl Equipment {
+    pub fn parse(data: &str) -> Result<Self, ParseError> {
+        let data = data.trim();
+
+        // Checks that the profile is complete, and reads its weapons
+        let loadout = Loadout::from_ini_data(data).map_err(ParseError::Loadout)?;
+
+        // The parsed loadout keeps its wearables and perks to itself, so they're
+        // read from the key-value list it was built from
+        let profile = match parser::key_value(data) {
+            Ok((_, (_, Value::KVList(profile)))) => profile,
+            _ => return Err(ParseError::Loadout(Error::Incomplete("profile"))),
+        };
+
+        let wearables = match field(&profile, "GearCustomization") {
+            Some(Value::KVList(gear)) => match field(gear, "Wearables") {
+                Some(Value::VList(wearables)) => wearables.as_slice(),
+                _ => &[][..],
+            },
+            _ => &[][..],
+        };
+
+        let wearable = |slot: WearableSlot, name: &'static str| match wearables.get(slot as usize) {
+            Some(Value::KVList(wearable)) => match field(wearable, "ID") {
+                Some(Value::Int(id)) => Ok(*id),
+                _ => Err(ParseError::MissingWearable(name)),
+            },
+            _ => Err(ParseError::MissingWearable(name)),
+        };
+
+        let perks = match field(&profile, "SkillsCustomization") {
+            Some(Value::KVList(skills)) => match field(skills, "Perks") {
+                Some(Value::Int(mask)) => *mask as u32,
+                _ => 0,
+            },
+            _ => 0,
+        };
+
+        Ok(Equipment {
+            weapons: loadout.weapons.iter().map(|w| w.id).collect(),
+            armor: Armor {
+                head: wearable(WearableSlot::Head, "head")?,
+                torso: wearable(WearableSlot::Torso, "torso")?,
+                legs: wearable(WearableSlot::Legs, "legs")?,
+            },
+            perks: (0..32).filter(|bit| perks & (1 << bit) != 0).collect(),
+        })
+    }
+}
+
+// Normalise an item name for lookups, so that "Flesh Wound", "flesh_wound" and
+// "FleshWound" are all treated the same
+pub fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn field<'a>(list: &'a KVList, key: &str) -> Option<&'a Value> {
+    list.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A profile as exported by the game, with the wearables, equipment and
+    // perks swapped in
+    fn profile(wearables: &str, equipment: &str, perks: u32) -> String {
+        format!(
+            "CharacterProfiles=(Name=INVTEXT(\"Test\"),\
+             GearCustomization=(Wearables=({}),Equipment=({})),\
+             AppearanceCustomization=(Emblem=47,EmblemColors=(1,9),MetalRoughnessScale=255,MetalTint=4,Age=0,Voice=4,VoicePitch=222,bIsFemale=False,Fat=85,Skinny=85,Strong=85,SkinColor=0,Face=0,EyeColor=0,HairColor=0,Hair=0,FacialHair=0,Eyebrows=0),\
+             FaceCustomization=(Translate=(0,0),Rotate=(0,0),Scale=(0,0)),\
+             SkillsCustomization=(Perks={}))",
+            wearables, equipment, perks
+        )
+    }
+
+    const WEARABLES: &str = "(ID=39,Colors=(9,1)),(ID=4,Colors=(9,0)),(ID=31,Colors=(9,9)),\
+                             (ID=10,Colors=(9,9)),(ID=34,Colors=(9,0)),(ID=10,Colors=(0,9)),\
+                             (ID=14,Colors=(9,0)),(ID=4,Colors=(9,9)),(ID=1,Colors=(9,10))";
+
+    #[test]
+    fn parses_an_exported_profile() {
+        let data = profile(WEARABLES, "(ID=26,Parts=(2,0,1)),(ID=58),()", 0);
+        let equipment = Equipment::parse(&data).unwrap();
+
+        assert_eq!(equipment.weapons, vec![26, 58]);
+        assert_eq!(
+            equipment.armor,
+            Armor {
+                head: 39,
+                torso: 31,
+                legs: 4,
+            }
+        );
+        assert!(equipment.perks.is_empty());
+    }
+
+    #[test]
+    fn reads_perks_from_the_mask() {
+        let data = profile(WEARABLES, "(ID=26)", 0b1_0000_0101);
+        let equipment = Equipment::parse(&data).unwrap();
+
+        assert_eq!(equipment.perks, vec![0, 2, 8]);
+    }
+
+    #[test]
+    fn keeps_repeated_weapons() {
+        // Duplicates are for the cost report to point out, not dropped here
+        let data = profile(WEARABLES, "(ID=26),(ID=26)", 0);
+        let equipment = Equipment::parse(&data).unwrap();
+
+        assert_eq!(equipment.weapons, vec![26, 26]);
+    }
+
+    #[test]
+    fn rejects_missing_armor() {
+        let data = profile("(ID=39,Colors=(9,1)),(ID=4,Colors=(9,0))", "(ID=26)", 0);
+
+        match Equipment::parse(&data) {
+            Err(ParseError::MissingWearable(slot)) => assert_eq!(slot, "torso"),
+            other => panic!("expected a missing wearable, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_other_data() {
+        assert!(Equipment::parse("").is_err());
+        assert!(Equipment::parse("Weapons=Longsword").is_err());
+        assert!(Equipment::parse("CharacterProfiles=(Name=INVTEXT(\"Test\"))").is_err());
+    }
+
+    #[test]
+    fn normalizes_names() {
+        assert_eq!(normalize("Flesh Wound"), "fleshwound");
+        assert_eq!(normalize("flesh_wound"), "fleshwound");
+        assert_eq!(normalize("FleshWound"), "fleshwound");
+    }
+}
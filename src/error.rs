@@ -1,4 +1,9 @@
-use crate::files;
+use crate::{
+    db::ManagerError,
+    files::{self, FileError},
+    logging,
+    metrics,
+};
 use actix_web::{error::BlockingError, HttpResponse, ResponseError};
 
 #[derive(Debug, Fail)]
@@ -11,8 +16,12 @@ pub enum Error {
     CanceledBlock,
     #[fail(display = "404 not found")]
     NotFound,
+    #[fail(display = "bad request: {}", _0)]
+    BadRequest(String),
     #[fail(display = "unauthorized")]
     Unauthorized,
+    #[fail(display = "403 forbidden")]
+    Forbidden,
     #[fail(display = "unknown internal error")]
     Internal,
     #[fail(display = "nothing returned from database")]
@@ -21,15 +30,49 @@ pub enum Error {
     RedirectToLogin,
     #[fail(display = "failed to authenticate with steam: {}", _0)]
     SteamAuth(steam_auth::Error),
+    #[fail(display = "file error: {}", _0)]
+    File(FileError),
+    #[fail(display = "timed out waiting for a database connection")]
+    PoolTimeout,
+    #[fail(display = "the database connection pool is closing")]
+    PoolClosing,
+    #[fail(display = "rate limited, retry after {}s", _0)]
+    RateLimited(u64),
 }
 
 impl ResponseError for Error {
     fn error_response(&self) -> HttpResponse {
+        if !self.is_expected() {
+            metrics::ERRORS.with_label_values(&[self.kind()]).inc();
+        }
+
         #[allow(unreachable_patterns)]
         match self {
             Error::NotFound => HttpResponse::NotFound()
                 .content_type("text/html")
-                .body(files::read("static/404.html")),
+                .body(error_page(files::NOT_FOUND_PAGE, "404 Not Found")),
+
+            Error::Forbidden => HttpResponse::Forbidden()
+                .content_type("text/html")
+                .body(error_page(files::FORBIDDEN_PAGE, "403 Forbidden")),
+
+            x @ Error::BadRequest(_) => HttpResponse::BadRequest().body(x.to_string()),
+
+            Error::PoolTimeout | Error::PoolClosing => HttpResponse::ServiceUnavailable()
+                .header("Retry-After", "5")
+                .content_type("text/html")
+                .body(error_page(
+                    files::ERROR_PAGE,
+                    "The server is busy, please try again shortly",
+                )),
+
+            Error::RateLimited(retry_after) => HttpResponse::TooManyRequests()
+                .header("Retry-After", retry_after.to_string())
+                .content_type("text/html")
+                .body(error_page(
+                    files::ERROR_PAGE,
+                    "Too many requests, please slow down",
+                )),
 
             Error::RedirectToLogin => HttpResponse::Found()
                 .header("Location", "/auth/login")
@@ -42,12 +85,16 @@ impl ResponseError for Error {
             Error::Template(e) => HttpResponse::InternalServerError().body(e.to_string()),
 
             #[cfg(debug_assertions)]
-            x @ Error::CanceledBlock | x @ Error::Unauthorized => {
+            x @ Error::CanceledBlock | x @ Error::Unauthorized | x @ Error::File(_) => {
                 HttpResponse::InternalServerError().body(x.to_string())
             }
 
-            // TODO: Render a nice HTML file instead in release
-            _ => HttpResponse::InternalServerError().body("Unknown internal server error"),
+            _ => HttpResponse::InternalServerError()
+                .content_type("text/html")
+                .body(error_page(
+                    files::ERROR_PAGE,
+                    "Unknown internal server error",
+                )),
         }
     }
 
@@ -56,6 +103,30 @@ impl ResponseError for Error {
     }
 }
 
+// The body of an error page, or plain text if the page can't be read. Both
+// include the request id, so that it can be quoted when reporting a problem.
+fn error_page(path: &str, fallback: &str) -> String {
+    let id = logging::request_id();
+
+    match files::read(path) {
+        Ok(page) => match id {
+            Some(id) => page.to_string().replacen(
+                "</body>",
+                &format!("\t<p>Request ID: <code>{}</code></p>\n</body>", id),
+                1,
+            ),
+            None => page.to_string(),
+        },
+        Err(e) => {
+            error!("{}", e);
+            match id {
+                Some(id) => format!("{} (request id {})", fallback, id),
+                None => fallback.to_owned(),
+            }
+        }
+    }
+}
+
 impl From<Error> for HttpResponse {
     fn from(e: Error) -> HttpResponse {
         e.error_response()
@@ -71,15 +142,23 @@ impl From<BlockingError<Error>> for Error {
     }
 }
 
-impl From<l337::Error<tokio_postgres::Error>> for Error {
-    fn from(e: l337::Error<tokio_postgres::Error>) -> Self {
+impl From<l337::Error<ManagerError>> for Error {
+    fn from(e: l337::Error<ManagerError>) -> Self {
         match e {
             l337::Error::Internal(_) => Error::Internal,
-            l337::Error::External(e) => Error::Database(e),
+            l337::Error::External(ManagerError::Postgres(e)) => Error::Database(e),
+            l337::Error::External(ManagerError::TimedOut) => Error::PoolTimeout,
+            l337::Error::External(ManagerError::Closing) => Error::PoolClosing,
         }
     }
 }
 
+impl From<FileError> for Error {
+    fn from(e: FileError) -> Self {
+        Error::File(e)
+    }
+}
+
 impl From<tokio_postgres::Error> for Error {
     fn from(e: tokio_postgres::Error) -> Self {
         Error::Database(e)
@@ -87,6 +166,38 @@ impl From<tokio_postgres::Error> for Error {
 }
 
 impl Error {
+    // Used to label error metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Database(_) => "database",
+            Error::Template(_) => "template",
+            Error::CanceledBlock => "canceled_block",
+            Error::NotFound => "not_found",
+            Error::BadRequest(_) => "bad_request",
+            Error::Unauthorized => "unauthorized",
+            Error::Forbidden => "forbidden",
+            Error::Internal => "internal",
+            Error::DbNothingReturned => "db_nothing_returned",
+            Error::RedirectToLogin => "redirect_to_login",
+            Error::SteamAuth(_) => "steam_auth",
+            Error::File(_) => "file",
+            Error::PoolTimeout => "pool_timeout",
+            Error::PoolClosing => "pool_closing",
+            Error::RateLimited(_) => "rate_limited",
+        }
+    }
+
+    // Errors that are a normal part of using the site rather than a sign that
+    // something's wrong, so they aren't counted in the error metrics
+    fn is_expected(&self) -> bool {
+        match self {
+            Error::NotFound | Error::Forbidden | Error::RedirectToLogin | Error::RateLimited(_) => {
+                true
+            }
+            _ => false,
+        }
+    }
+
     pub fn db_or_404(e: tokio_postgres::Error) -> Self {
         match e {
             // TODO: Work out what the error code is
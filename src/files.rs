@@ -1,41 +1,139 @@
-use std::{collections::HashMap, fs, sync::RwLock};
+// Small files served straight from disk, like error pages and robots.txt.
+// Files are read once when registered and then kept in memory. In debug
+// builds they're checked for changes every time they're read, so edits show
+// up without restarting the server.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+pub const NOT_FOUND_PAGE: &str = "static/404.html";
+pub const FORBIDDEN_PAGE: &str = "static/403.html";
+pub const ERROR_PAGE: &str = "static/500.html";
+pub const ROBOTS_TXT: &str = "static/robots.txt";
 
 lazy_static::lazy_static! {
-    static ref FILES: FileCache<'static> = {
-        FileCache::new()
-            .file("static/404.html")
+    static ref FILES: FileCache = {
+        let cache = FileCache::new();
+
+        // A missing file shouldn't stop the server from starting, it'll just
+        // be an error whenever it's read
+        for path in &[NOT_FOUND_PAGE, FORBIDDEN_PAGE, ERROR_PAGE, ROBOTS_TXT] {
+            if let Err(e) = cache.register(path) {
+                error!("{}", e);
+            }
+        }
+
+        cache
     };
 }
 
-struct FileCache<'a> {
-    pub files: RwLock<HashMap<&'a str, String>>,
+#[derive(Debug, Fail)]
+pub enum FileError {
+    #[fail(display = "'{}' is not registered with the file cache", _0)]
+    NotRegistered(String),
+    #[fail(display = "failed to read '{}': {}", _0, _1)]
+    Io(String, io::Error),
+}
+
+struct Entry {
+    data: Arc<String>,
+    modified: Option<SystemTime>,
+}
+
+impl Entry {
+    fn load(path: &str) -> Result<Self, FileError> {
+        let data = fs::read_to_string(path).map_err(|e| FileError::Io(path.to_owned(), e))?;
+
+        Ok(Self {
+            data: Arc::new(data),
+            modified: modified(path),
+        })
+    }
+
+    fn is_stale(&self, path: &str) -> bool {
+        modified(path) != self.modified
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
 }
 
-impl<'a> FileCache<'a> {
+#[derive(Default)]
+pub struct FileCache {
+    // Registered files that couldn't be read are kept as `None`, and retried
+    // on the next read
+    files: RwLock<HashMap<String, Option<Entry>>>,
+}
+
+impl FileCache {
     pub fn new() -> Self {
-        Self {
-            files: RwLock::new(HashMap::new()),
-        }
+        Self::default()
+    }
+
+    pub fn register(&self, path: &str) -> Result<(), FileError> {
+        let (entry, result) = match Entry::load(path) {
+            Ok(entry) => (Some(entry), Ok(())),
+            Err(e) => (None, Err(e)),
+        };
+
+        self.files.write().unwrap().insert(path.to_owned(), entry);
+        result
+    }
+
+    pub fn check(&self) -> Vec<FileError> {
+        let paths = self
+            .files
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        paths
+            .iter()
+            .filter_map(|path| self.get(path).err())
+            .collect()
     }
 
-    pub fn file(self, path: &'a str) -> Self {
-        match fs::read_to_string(path) {
-            Ok(data) => {
-                self.files.write().unwrap().insert(path, data);
+    pub fn get(&self, path: &str) -> Result<Arc<String>, FileError> {
+        {
+            let files = self.files.read().unwrap();
+            let entry = files
+                .get(path)
+                .ok_or_else(|| FileError::NotRegistered(path.to_owned()))?;
+
+            if let Some(entry) = entry {
+                if !cfg!(debug_assertions) || !entry.is_stale(path) {
+                    return Ok(entry.data.clone());
+                }
             }
-            Err(e) => panic!("no file found at location {}: {}", path, e),
         }
 
-        self
+        // Not loaded yet, or changed on disk since it was
+        let entry = Entry::load(path)?;
+        let data = entry.data.clone();
+
+        debug!("loaded {} into the file cache", path);
+        self.files
+            .write()
+            .unwrap()
+            .insert(path.to_owned(), Some(entry));
+
+        Ok(data)
     }
 }
 
-pub fn read(path: &str) -> String {
-    match FILES.files.read().unwrap().get(path) {
-        Some(data) => data.clone(),
-        None => {
-            error!("no file found in cache named '{}'", path);
-            "internal error".to_owned()
-        }
-    }
+pub fn read(path: &str) -> Result<Arc<String>, FileError> {
+    FILES.get(path)
+}
+
+// Errors for any registered files that can't be read
+pub fn check() -> Vec<FileError> {
+    FILES.check()
 }
@@ -0,0 +1,234 @@
+// HTTPS support. Each request is checked for whether it came over HTTPS,
+// either directly over TLS (`TLS_CERT_FILE` and `TLS_KEY_FILE` set), or
+// through a proxy in `TRUSTED_PROXIES` which terminated TLS and says so with
+// `X-Forwarded-Proto`. Responses to those get an HSTS header and have their
+// cookies marked secure. With an https `SITE_URL`, plain HTTP requests are
+// redirected to it.
+
+use crate::app::SITE_URL;
+use actix_web::{
+    cookie::Cookie,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{self, HeaderValue},
+    HttpResponse,
+};
+use futures::{
+    future::{ok, Either, FutureResult},
+    Future,
+    Poll,
+};
+use openssl::{
+    error::ErrorStack,
+    ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod},
+};
+use std::{env, net::IpAddr};
+
+pub const HTTPS_ADDR: &str = "0.0.0.0:3443";
+
+const HSTS: &str = "max-age=31536000; includeSubDomains";
+
+// Checked by load balancers and monitoring, which talk plain HTTP
+const EXEMPT_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+lazy_static::lazy_static! {
+    static ref CONFIG: Config = Config::from_env();
+}
+
+struct Config {
+    tls_files: Option<(String, String)>,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let tls_files = match (env::var("TLS_CERT_FILE"), env::var("TLS_KEY_FILE")) {
+            (Ok(cert), Ok(key)) => Some((cert, key)),
+            (Err(_), Err(_)) => None,
+            _ => panic!("TLS_CERT_FILE and TLS_KEY_FILE must be set together"),
+        };
+
+        let trusted_proxies = env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .map(|ip| {
+                ip.parse()
+                    .unwrap_or_else(|e| panic!("invalid ip '{}' in TRUSTED_PROXIES: {}", ip, e))
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            tls_files,
+            trusted_proxies,
+        }
+    }
+
+    // Only redirect when we'd be able to tell that the redirected request is
+    // secure, otherwise it would loop forever
+    fn redirect(&self) -> bool {
+        SITE_URL.starts_with("https://")
+            && (self.tls_files.is_some() || !self.trusted_proxies.is_empty())
+    }
+
+    fn is_secure(&self, req: &ServiceRequest) -> bool {
+        if req.app_config().secure() {
+            return true;
+        }
+
+        let trusted = req
+            .peer_addr()
+            .map_or(false, |addr| self.trusted_proxies.contains(&addr.ip()));
+
+        trusted
+            && req
+                .headers()
+                .get("x-forwarded-proto")
+                .and_then(|proto| proto.to_str().ok())
+                .map_or(false, |proto| proto.eq_ignore_ascii_case("https"))
+    }
+
+    fn client_ip(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        if !self.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+
+        let forwarded = req
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|header| header.to_str().ok())
+            .unwrap_or_default();
+
+        // Each proxy appends the address it got the request from, so the
+        // client is the last one that isn't one of ours. Anything before
+        // that could have been made up by the client.
+        for ip in forwarded.rsplit(',').map(str::trim) {
+            match ip.parse() {
+                Ok(ip) if self.trusted_proxies.contains(&ip) => continue,
+                Ok(ip) => return Some(ip),
+                Err(_) => break,
+            }
+        }
+
+        Some(peer)
+    }
+}
+
+// The address of whoever made the request, looking through trusted proxies
+pub fn client_ip(req: &ServiceRequest) -> Option<IpAddr> {
+    CONFIG.client_ip(req)
+}
+
+// The TLS acceptor to listen on `HTTPS_ADDR` with, if certificates are configured
+pub fn acceptor() -> Option<Result<SslAcceptorBuilder, ErrorStack>> {
+    CONFIG.tls_files.as_ref().map(|(cert, key)| {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        builder.set_private_key_file(key, SslFiletype::PEM)?;
+        builder.set_certificate_chain_file(cert)?;
+        Ok(builder)
+    })
+}
+
+pub struct Https;
+
+impl<S, B> Transform<S> for Https
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = HttpsMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(HttpsMiddleware { service })
+    }
+}
+
+pub struct HttpsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for HttpsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Either<
+        Box<dyn Future<Item = Self::Response, Error = Self::Error>>,
+        FutureResult<Self::Response, Self::Error>,
+    >;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if CONFIG.is_secure(&req) {
+            return Either::A(Box::new(self.service.call(req).map(|mut res| {
+                res.headers_mut().insert(
+                    header::STRICT_TRANSPORT_SECURITY,
+                    HeaderValue::from_static(HSTS),
+                );
+                secure_cookies(&mut res);
+                res
+            })));
+        }
+
+        if !CONFIG.redirect() || EXEMPT_PATHS.contains(&req.path()) {
+            return Either::A(Box::new(self.service.call(req)));
+        }
+
+        // Redirect to `SITE_URL` rather than the Host header, which we can't trust
+        let location = match req.uri().path_and_query() {
+            Some(path) => format!("{}{}", *SITE_URL, path),
+            None => SITE_URL.clone(),
+        };
+
+        Either::B(ok(req.into_response(
+            HttpResponse::MovedPermanently()
+                .header(header::LOCATION, location)
+                .finish()
+                .into_body(),
+        )))
+    }
+}
+
+// Mark every cookie in the response secure, so that browsers which got them
+// over HTTPS never send them back over plain HTTP
+fn secure_cookies<B>(res: &mut ServiceResponse<B>) {
+    let cookies = res
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .map(|value| {
+            let cookie = value
+                .to_str()
+                .ok()
+                .and_then(|value| Cookie::parse(value).ok());
+
+            match cookie {
+                Some(mut cookie) => {
+                    cookie.set_secure(true);
+                    HeaderValue::from_str(&cookie.to_string()).unwrap_or_else(|_| value.clone())
+                }
+                // Left as it is, rather than dropped
+                None => value.clone(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let headers = res.headers_mut();
+    headers.remove(header::SET_COOKIE);
+    for cookie in cookies {
+        headers.append(header::SET_COOKIE, cookie);
+    }
+}
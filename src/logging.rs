@@ -0,0 +1,256 @@
+// Logging, with an id for each request. The id is taken from `X-Request-Id` if
+// the client or a proxy sent a sensible one, and is added to every line
+// logged while handling the request, to error pages, and to the response.
+//
+// Levels come from `RUST_LOG` as usual, defaulting to `DEFAULT_FILTER`. Lines
+// are plain text, or JSON objects if `LOG_FORMAT=json`, in which case access
+// log lines also get their own fields.
+
+use crate::metrics;
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    HttpRequest,
+};
+use env_logger::{fmt::Formatter, Env};
+use futures::{
+    future::{ok, FutureResult},
+    Future,
+    Poll,
+};
+use log::Record;
+use std::{
+    cell::RefCell,
+    env,
+    io::{self, Write},
+    rc::Rc,
+    time::Instant,
+};
+use uuid::Uuid;
+
+const DEFAULT_FILTER: &str = "mordhub=debug,actix_web=error";
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longer ids from clients are replaced rather than logged
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+thread_local! {
+    // The id of the request being handled on this thread, set while polling
+    // its future
+    static REQUEST_ID: RefCell<Option<Rc<str>>> = RefCell::new(None);
+
+    // Fields for the access log line being written, see `log_access`
+    static ACCESS: RefCell<Option<Access>> = RefCell::new(None);
+}
+
+#[derive(Debug, Serialize)]
+struct Access {
+    method: String,
+    path: String,
+    route: String,
+    status: u16,
+    latency_ms: f64,
+    user_id: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: String,
+    level: String,
+    target: &'a str,
+    message: String,
+    request_id: Option<String>,
+    #[serde(flatten)]
+    access: Option<&'a Access>,
+}
+
+// The logged in user, see `record_user`
+struct LoggedInUser(i32);
+
+pub fn init() {
+    let mut builder =
+        env_logger::Builder::from_env(Env::default().default_filter_or(DEFAULT_FILTER));
+
+    match env::var("LOG_FORMAT") {
+        Ok(ref format) if format == "json" => builder.format(format_json),
+        _ => builder.format(format_text),
+    };
+
+    builder.init();
+}
+
+// The id of the request currently being handled, if any
+pub fn request_id() -> Option<Rc<str>> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+// Called by the `User` extractor, so that the access log can say who made the
+// request
+pub fn record_user(req: &HttpRequest, user_id: i32) {
+    req.extensions_mut().insert(LoggedInUser(user_id));
+}
+
+fn format_text(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let id = request_id()
+        .map(|id| format!(" {}", id))
+        .unwrap_or_default();
+
+    writeln!(
+        buf,
+        "[{} {:<5} {}{}] {}",
+        buf.timestamp(),
+        record.level(),
+        record.target(),
+        id,
+        record.args()
+    )
+}
+
+fn format_json(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let line = ACCESS.with(|access| {
+        serde_json::to_string(&JsonLine {
+            timestamp: buf.timestamp().to_string(),
+            level: record.level().to_string(),
+            target: record.target(),
+            message: record.args().to_string(),
+            request_id: request_id().map(|id| id.to_string()),
+            access: access.borrow().as_ref(),
+        })
+    })?;
+
+    writeln!(buf, "{}", line)
+}
+
+fn log_access(access: Access) {
+    let message = format!(
+        "{} {} {} in {:.1}ms",
+        access.method, access.path, access.status, access.latency_ms
+    );
+
+    ACCESS.with(|a| *a.borrow_mut() = Some(access));
+    info!(target: "mordhub::access", "{}", message);
+    ACCESS.with(|a| a.borrow_mut().take());
+}
+
+// Run `f` as part of handling the request with id `id`
+fn with_request_id<T, F: FnOnce() -> T>(id: &Rc<str>, f: F) -> T {
+    let previous = REQUEST_ID.with(|current| current.replace(Some(id.clone())));
+    let result = f();
+    REQUEST_ID.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+// Ids from outside are only used if they can't mess up logs or pages
+fn incoming_request_id(req: &ServiceRequest) -> Option<String> {
+    let id = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+
+    let valid = !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+
+    if valid {
+        Some(id.to_owned())
+    } else {
+        None
+    }
+}
+
+// Gives each request an id, and writes an access log line once it's handled
+pub struct RequestId;
+
+impl<S, B> Transform<S> for RequestId
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = RequestIdMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware { service })
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestIdMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = WithRequestId<Box<dyn Future<Item = Self::Response, Error = Self::Error>>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let id: Rc<str> = incoming_request_id(&req)
+            .unwrap_or_else(|| Uuid::new_v4().to_string())
+            .into();
+
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let path = req.path().to_owned();
+        let header = HeaderValue::from_str(&id).ok();
+
+        let service = &mut self.service;
+        let res = with_request_id(&id, move || service.call(req));
+
+        WithRequestId {
+            id,
+            inner: Box::new(res.map(move |mut res| {
+                if let Some(header) = header {
+                    res.headers_mut()
+                        .insert(HeaderName::from_static(REQUEST_ID_HEADER), header);
+                }
+
+                log_access(Access {
+                    route: metrics::recorded_route(res.request()),
+                    status: res.status().as_u16(),
+                    latency_ms: metrics::seconds_since(start) * 1e3,
+                    user_id: res
+                        .request()
+                        .extensions()
+                        .get::<LoggedInUser>()
+                        .map(|user| user.0),
+                    method,
+                    path,
+                });
+
+                res
+            })),
+        }
+    }
+}
+
+// A request's future, which makes its id available to anything logged while
+// it's being polled
+pub struct WithRequestId<F> {
+    id: Rc<str>,
+    inner: F,
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = &mut self.inner;
+        with_request_id(&self.id, move || inner.poll())
+    }
+}
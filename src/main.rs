@@ -6,66 +6,191 @@ extern crate serde;
 extern crate failure;
 #[macro_use]
 extern crate derive_builder;
+#[macro_use]
+extern crate prometheus;
 
 mod app;
+mod assets;
+mod cache;
+mod catalog;
+mod cost;
+#[macro_use]
 mod db;
+mod equipment;
 mod error;
 mod files;
+mod https;
+mod logging;
+mod markdown;
+mod metrics;
 mod models;
+mod ratelimit;
 mod routes;
+mod search;
+mod shutdown;
+mod steam;
 
-use actix_files as fs;
 use actix_web::{
     cookie::SameSite,
     guard,
-    middleware::{
-        self,
-        identity::{CookieIdentityPolicy, IdentityService},
-    },
+    middleware::identity::{CookieIdentityPolicy, IdentityService},
     web,
     App,
-    HttpResponse,
     HttpServer,
-    ResponseError,
 };
 use dotenv::dotenv;
+use std::time::Duration;
 
-fn main() {
-    std::env::set_var("RUST_LOG", "mordhub=debug,actix_web=error");
+// How long a request waits for a database connection, unless overridden by
+// `DATABASE_POOL_TIMEOUT` (in seconds)
+const DEFAULT_POOL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How far behind the primary the replica can get before reads go back to the
+// primary, unless overridden by `DATABASE_REPLICA_MAX_LAG` (in seconds)
+const DEFAULT_REPLICA_MAX_LAG: Duration = Duration::from_secs(10);
+
+// How long in-flight requests get to finish when shutting down, unless
+// overridden by `SHUTDOWN_GRACE_PERIOD` (in seconds)
+const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
 
+// Where `/metrics` is served, unless overridden by `METRICS_ADDR`. It's kept off
+// the public port, as it says more about the site than visitors need to know.
+const DEFAULT_METRICS_ADDR: &str = "127.0.0.1:9000";
+
+fn main() {
     dotenv().ok();
-    env_logger::init();
+    logging::init();
 
     let mut system = actix_rt::System::new("MordHub");
 
-    let db_cfg = std::env::var("DATABASE_URL")
-        .unwrap()
-        .parse()
-        .expect("failed to parse db url");
+    let (db_cfg, tls) = db::tls::configure(
+        &std::env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
+    )
+    .expect("failed to configure database connection");
 
-    let mgr = db::ConnectionManager::new(db_cfg, tokio_postgres::NoTls);
+    let mgr = db::ConnectionManager::new(db_cfg, tls);
 
     let pool_cfg = l337::Config {
         min_size: 4,
         max_size: 32,
     };
 
+    let pool_timeout = std::env::var("DATABASE_POOL_TIMEOUT")
+        .ok()
+        .map(|secs| secs.parse().expect("failed to parse DATABASE_POOL_TIMEOUT"))
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_POOL_TIMEOUT);
+
     let pool = system
-        .block_on(l337::Pool::new(mgr, pool_cfg))
+        .block_on(db::Pool::connect(mgr, pool_cfg, "primary", pool_timeout))
         .expect("db connection error");
 
-    let redirector = steam_auth::Redirector::new(
-        std::env::var("SITE_URL").expect("SITE_URL is not set"),
-        "/auth/callback",
-    )
-    .expect("failed to build steam auth redirector");
+    // Reads can be sent to a replica if there is one. Its url takes the same
+    // tls options as `DATABASE_URL`.
+    let replica = std::env::var("DATABASE_REPLICA_URL").ok().and_then(|url| {
+        let (replica_cfg, tls) = db::tls::configure(&url)
+            .expect("failed to configure database replica connection");
+        let mgr = db::ConnectionManager::new(replica_cfg, tls).read_only();
+
+        // No connections are made up front, so that the pool can be created
+        // while the replica is down. It's checked in the background, and only
+        // used once it's reachable.
+        let pool_cfg = l337::Config {
+            min_size: 0,
+            max_size: 32,
+        };
+
+        let max_lag = std::env::var("DATABASE_REPLICA_MAX_LAG")
+            .ok()
+            .map(|secs| secs.parse().expect("failed to parse DATABASE_REPLICA_MAX_LAG"))
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_REPLICA_MAX_LAG);
+
+        match system.block_on(db::Pool::connect(mgr, pool_cfg, "replica", pool_timeout)) {
+            Ok(pool) => Some(db::Replica::new(pool, max_lag)),
+            Err(e) => {
+                error!(
+                    "failed to create the replica pool, sending all queries to the primary: {}",
+                    e
+                );
+                None
+            }
+        }
+    });
+
+    if let Err(e) = assets::init() {
+        error!(
+            "failed to load static files from {}: {}",
+            assets::dir().display(),
+            e
+        );
+        std::process::exit(1);
+    }
+
+    if let Err(e) = catalog::init() {
+        error!(
+            "failed to load game data from {}: {}",
+            catalog::dir().display(),
+            e
+        );
+        std::process::exit(1);
+    }
+
+    let caches = cache::Caches::default();
+
+    let grace_period = std::env::var("SHUTDOWN_GRACE_PERIOD")
+        .ok()
+        .map(|secs| secs.parse().expect("failed to parse SHUTDOWN_GRACE_PERIOD"))
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SHUTDOWN_GRACE_PERIOD);
 
+    // The server's copies are moved into it, these are for shutting down
+    let (shutdown_pool, shutdown_replica) = (pool.clone(), replica.clone());
+
+    let redirector = steam_auth::Redirector::new(app::SITE_URL.as_str(), "/auth/callback")
+        .expect("failed to build steam auth redirector");
+
+    let metrics_addr =
+        std::env::var("METRICS_ADDR").unwrap_or_else(|_| DEFAULT_METRICS_ADDR.to_owned());
+
+    let (metrics_pool, metrics_replica, metrics_caches, metrics_redirector) = (
+        pool.clone(),
+        replica.clone(),
+        caches.clone(),
+        redirector.clone(),
+    );
+
+    // Stopped along with the system once the main server has shut down
     HttpServer::new(move || {
-        let state = app::State::new(pool.clone(), redirector.clone());
+        let state = app::State::new(
+            metrics_pool.clone(),
+            metrics_replica.clone(),
+            metrics_caches.clone(),
+            metrics_redirector.clone(),
+        );
 
         App::new()
             .data(state)
-            .wrap(middleware::Logger::default())
+            .route("/metrics", web::get().to(routes::meta::metrics))
+    })
+    .disable_signals()
+    .bind(&metrics_addr)
+    .unwrap_or_else(|e| panic!("can't bind to {}: {}", metrics_addr, e))
+    .start();
+
+    let server = HttpServer::new(move || {
+        let state = app::State::new(
+            pool.clone(),
+            replica.clone(),
+            caches.clone(),
+            redirector.clone(),
+        );
+
+        App::new()
+            .data(state)
+            .wrap(metrics::RequestMetrics)
+            // Inside `IdentityService`, so that it can tell who's logged in
+            .wrap(ratelimit::RateLimit)
             .wrap(IdentityService::new(
                 CookieIdentityPolicy::new(
                     std::env::var("COOKIE_SECRET")
@@ -74,11 +199,19 @@ fn main() {
                 )
                 .name("auth-cookie")
                 .same_site(SameSite::Lax) // CSRF mitigation (TODO: add form token mitigation as well)
-                .secure(false), // TODO: Use TLS and make this true
+                .secure(false), // Identity cookies are always HttpOnly, and `Https` marks them secure
             ))
+            // Outside `IdentityService`, so that it sees the identity cookie
+            .wrap(https::Https)
+            // Outermost, so that everything else logs with the request id
+            .wrap(logging::RequestId)
             // Meta
             .route("/", web::get().to(routes::meta::index))
             .route("/about", web::get().to(routes::meta::about))
+            .route("/robots.txt", web::get().to(routes::meta::robots))
+            // Health checks
+            .route("/healthz", web::get().to(routes::health::healthz))
+            .route("/readyz", web::get().to_async(routes::health::readyz))
             // Auth
             .route("/auth/login", web::get().to(routes::auth::login))
             .route("/auth/logout", web::get().to(routes::auth::logout))
@@ -101,31 +234,124 @@ fn main() {
                 "/loadouts/create",
                 web::post().to_async(routes::loadout::create_post),
             )
+            .route(
+                "/loadouts/compare",
+                web::get().to_async(routes::compare::compare),
+            )
+            .route(
+                "/loadouts/patch-report",
+                web::get().to_async(routes::loadout::patch_report),
+            )
             .route(
                 "/loadouts/{id}",
                 web::get().to_async(routes::loadout::single),
             )
+            .route(
+                "/loadouts/{id}/edit",
+                web::get().to_async(routes::loadout::edit_get),
+            )
+            .route(
+                "/loadouts/{id}/edit",
+                web::post().to_async(routes::loadout::edit_post),
+            )
+            .route(
+                "/loadouts/{id}/delete",
+                web::post().to_async(routes::loadout::delete),
+            )
+            .route(
+                "/loadouts/{id}/like",
+                web::post().to_async(routes::loadout::toggle_like),
+            )
+            // Catalog
+            .route(
+                "/catalog/weapons/{name}",
+                web::get().to_async(routes::catalog::weapon),
+            )
             // Guides
             .route("/guides", web::get().to(routes::guides::list))
+            .route(
+                "/guides/community",
+                web::get().to_async(routes::guides::community_list),
+            )
+            .route(
+                "/guides/community/new",
+                web::get().to(routes::guides::community_create_get),
+            )
+            .route(
+                "/guides/community/new",
+                web::post().to_async(routes::guides::community_create_post),
+            )
+            .route(
+                "/guides/community/{id}",
+                web::get().to_async(routes::guides::community_single),
+            )
+            .route(
+                "/guides/community/{id}/edit",
+                web::get().to_async(routes::guides::community_edit_get),
+            )
+            .route(
+                "/guides/community/{id}/edit",
+                web::post().to_async(routes::guides::community_edit_post),
+            )
+            .route(
+                "/guides/community/{id}/revisions",
+                web::get().to_async(routes::revisions::list),
+            )
+            .route(
+                "/guides/community/{id}/revisions/{revision}",
+                web::get().to_async(routes::revisions::diff),
+            )
+            .route(
+                "/guides/community/{id}/revisions/{revision}/accept",
+                web::post().to_async(routes::revisions::accept),
+            )
+            .route(
+                "/guides/community/{id}/revisions/{revision}/reject",
+                web::post().to_async(routes::revisions::reject),
+            )
             .service(routes::gen::guides::scope())
+            // Search
+            .route("/search", web::get().to_async(routes::search::search))
             // API
             .route("/api/test", web::get().to(routes::api::test))
+            .route(
+                "/api/v1/loadouts/validate",
+                web::post().to(routes::api::validate_loadout),
+            )
+            .route("/api/v1/search", web::get().to_async(routes::api::search))
             // Static files
-            .service(fs::Files::new("/static", "./static/").index_file("404.html"))
+            .route("/static/{name:.*}", web::get().to(routes::assets::serve))
             // 404
             .default_service(
                 web::resource("")
-                    .route(web::get().to(|| app::Error::NotFound.render_response()))
+                    .route(web::get().to(routes::meta::not_found))
                     .route(
                         web::route()
                             .guard(guard::Not(guard::Get()))
-                            .to(HttpResponse::MethodNotAllowed),
+                            .to(routes::meta::method_not_allowed),
                     ),
             )
     })
+    // Signals are handled in `shutdown`, so that `/readyz` can fail first
+    .disable_signals()
+    .shutdown_timeout(grace_period.as_secs())
     .bind("0.0.0.0:3000")
-    .expect("can't bind to 0.0.0.0:3000")
-    .start();
+    .expect("can't bind to 0.0.0.0:3000");
+
+    let server = match https::acceptor() {
+        Some(acceptor) => {
+            let acceptor = acceptor.expect("failed to load tls certificate");
+            info!("Listening for https on {}", https::HTTPS_ADDR);
+            server
+                .bind_ssl(https::HTTPS_ADDR, acceptor)
+                .unwrap_or_else(|e| panic!("can't bind to {}: {}", https::HTTPS_ADDR, e))
+        }
+        None => server,
+    };
+
+    let server = server.start();
+    ratelimit::sweep_periodically();
+    shutdown::on_signal(server, shutdown_pool, shutdown_replica);
 
     println!("Starting server on 0.0.0.0:3000");
 
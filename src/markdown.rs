@@ -0,0 +1,21 @@
+// Markdown options shared between guides compiled by build.rs and guides
+// written on the site. build.rs includes this file directly, so it should only
+// depend on pulldown_cmark.
+
+use pulldown_cmark::{Options, Parser};
+
+pub fn options() -> Options {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_TASKLISTS);
+    options
+}
+
+// Render markdown to html. The output is not sanitized.
+pub fn to_html(markdown: &str) -> String {
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, Parser::new_ext(markdown, options()));
+    html
+}
@@ -0,0 +1,311 @@
+// Prometheus metrics, exposed at `/metrics` on `METRICS_ADDR`
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    HttpRequest,
+};
+use futures::{
+    future::{ok, FutureResult},
+    Future,
+    Poll,
+};
+use prometheus::{Gauge, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec};
+use std::time::Instant;
+
+lazy_static::lazy_static! {
+    pub static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "mordhub_http_requests_total",
+        "HTTP requests handled, by route, method and status code",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+
+    pub static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "mordhub_http_request_duration_seconds",
+        "Time taken to respond to HTTP requests, by route and method",
+        &["route", "method"]
+    )
+    .unwrap();
+
+    pub static ref ERRORS: IntCounterVec = register_int_counter_vec!(
+        "mordhub_errors_total",
+        "Unexpected errors returned from handlers, by kind",
+        &["kind"]
+    )
+    .unwrap();
+
+    pub static ref DB_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "mordhub_db_pool_connections",
+        "Open database connections, both idle and in use, by pool",
+        &["pool"]
+    )
+    .unwrap();
+
+    pub static ref DB_POOL_IDLE: IntGaugeVec = register_int_gauge_vec!(
+        "mordhub_db_pool_idle_connections",
+        "Open database connections that aren't in use, by pool",
+        &["pool"]
+    )
+    .unwrap();
+
+    pub static ref DB_POOL_WAITING: IntGaugeVec = register_int_gauge_vec!(
+        "mordhub_db_pool_waiting",
+        "Requests waiting for a database connection, by pool",
+        &["pool"]
+    )
+    .unwrap();
+
+    pub static ref DB_POOL_WAIT_DURATION: HistogramVec = register_histogram_vec!(
+        "mordhub_db_pool_wait_duration_seconds",
+        "Time spent waiting for a database connection, by pool",
+        &["pool"]
+    )
+    .unwrap();
+
+    pub static ref DB_REPLICA_LAG: Gauge = register_gauge!(
+        "mordhub_db_replica_lag_seconds",
+        "How far the read replica was behind the primary when last checked"
+    )
+    .unwrap();
+
+    pub static ref DB_REPLICA_HEALTHY: IntGauge = register_int_gauge!(
+        "mordhub_db_replica_healthy",
+        "Whether reads are being sent to the replica (1) or the primary (0)"
+    )
+    .unwrap();
+
+    pub static ref DB_QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "mordhub_db_query_duration_seconds",
+        "Time taken to run prepared statements, by statement name",
+        &["query"]
+    )
+    .unwrap();
+
+    pub static ref CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "mordhub_cache_lookups_total",
+        "In-process cache lookups, by cache and whether they hit or missed",
+        &["cache", "result"]
+    )
+    .unwrap();
+
+    pub static ref STEAM_AUTH: IntCounterVec = register_int_counter_vec!(
+        "mordhub_steam_auth_total",
+        "Steam login verifications, by result",
+        &["result"]
+    )
+    .unwrap();
+}
+
+pub fn seconds_since(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9
+}
+
+// Set on requests which didn't match any route, by the default service
+struct Unmatched;
+
+// The label a request was recorded under, for the access log
+struct Route(String);
+
+pub fn mark_unmatched(req: &HttpRequest) {
+    req.extensions_mut().insert(Unmatched);
+}
+
+// The route a request was recorded under by `RequestMetrics`. Requests which
+// never got that far (e.g redirected to HTTPS) weren't routed at all.
+pub fn recorded_route(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<Route>()
+        .map_or_else(|| "unmatched".to_owned(), |route| route.0.clone())
+}
+
+// The route that handled a request, e.g `/loadouts/{id}`. Built from the path
+// and the parameters matched in it, so that each route is a single label.
+// Anything which can't be turned back into its pattern is "unmatched", as
+// labelling it by its path would give a label per url.
+fn route_label(req: &HttpRequest) -> String {
+    if req.extensions().get::<Unmatched>().is_some() {
+        return "unmatched".to_owned();
+    }
+
+    let mut segments = req.path().split('/').map(str::to_owned).collect::<Vec<_>>();
+
+    // Working back from the end puts each parameter in the right place, even
+    // when its value is the same as an earlier segment
+    let params = req.match_info().iter().collect::<Vec<_>>();
+    let mut end = segments.len();
+
+    for (name, value) in params.into_iter().rev() {
+        let placeholder = format!("{{{}}}", name);
+
+        if let Some(i) = segments[..end].iter().rposition(|s| s.as_str() == value) {
+            segments[i] = placeholder;
+            end = i;
+        } else if let Some(start) = tail_start(&segments[..end], value) {
+            // Parameters like `/static/{name:.*}` can cover several segments
+            segments.truncate(start);
+            segments.push(placeholder);
+            end = start;
+        } else {
+            return "unmatched".to_owned();
+        }
+    }
+
+    segments.join("/")
+}
+
+// Clients can send any method, so only the standard ones get their own label
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::PATCH => "PATCH",
+        Method::OPTIONS => "OPTIONS",
+        Method::CONNECT => "CONNECT",
+        Method::TRACE => "TRACE",
+        _ => "other",
+    }
+}
+
+// Index of the segment that `value` starts at, if the path ends with it
+fn tail_start(segments: &[String], value: &str) -> Option<usize> {
+    let count = value.split('/').count();
+    let start = segments.len().checked_sub(count)?;
+
+    if segments[start..].join("/") == value {
+        Some(start)
+    } else {
+        None
+    }
+}
+
+// Records request counts and latencies for every route
+pub struct RequestMetrics;
+
+impl<S, B> Transform<S> for RequestMetrics
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = RequestMetricsMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestMetricsMiddleware { service })
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestMetricsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Box<dyn Future<Item = Self::Response, Error = Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let method = method_label(req.method());
+
+        Box::new(self.service.call(req).map(move |res| {
+            let route = route_label(res.request());
+            res.request().extensions_mut().insert(Route(route.clone()));
+
+            HTTP_REQUESTS
+                .with_label_values(&[&route, method, res.status().as_str()])
+                .inc();
+            HTTP_REQUEST_DURATION
+                .with_label_values(&[&route, method])
+                .observe(seconds_since(start));
+
+            res
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn labels_routes_by_pattern() {
+        let req = TestRequest::with_uri("/loadouts/5/edit")
+            .param("id", "5")
+            .to_http_request();
+
+        assert_eq!(route_label(&req), "/loadouts/{id}/edit");
+    }
+
+    #[test]
+    fn labels_repeated_values_in_place() {
+        let req = TestRequest::with_uri("/guides/community/3/revisions/3")
+            .param("guide_id", "3")
+            .param("revision_id", "3")
+            .to_http_request();
+
+        assert_eq!(
+            route_label(&req),
+            "/guides/community/{guide_id}/revisions/{revision_id}"
+        );
+    }
+
+    #[test]
+    fn labels_parameters_covering_several_segments() {
+        let req = TestRequest::with_uri("/static/css/site.css")
+            .param("name", "css/site.css")
+            .to_http_request();
+
+        assert_eq!(route_label(&req), "/static/{name}");
+    }
+
+    #[test]
+    fn labels_routes_without_parameters_by_path() {
+        let req = TestRequest::with_uri("/about").to_http_request();
+
+        assert_eq!(route_label(&req), "/about");
+    }
+
+    #[test]
+    fn labels_unmatched_requests() {
+        let req = TestRequest::with_uri("/no/such/page").to_http_request();
+        mark_unmatched(&req);
+        assert_eq!(route_label(&req), "unmatched");
+
+        // A parameter that isn't in the path can't be put back in its place
+        let req = TestRequest::with_uri("/loadouts/5")
+            .param("id", "6")
+            .to_http_request();
+        assert_eq!(route_label(&req), "unmatched");
+    }
+
+    #[test]
+    fn labels_unknown_methods_as_other() {
+        assert_eq!(method_label(&Method::GET), "GET");
+        assert_eq!(method_label(&Method::PATCH), "PATCH");
+        assert_eq!(
+            method_label(&Method::from_bytes(b"PROPFIND").unwrap()),
+            "other"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use crate::{
+    app::{self, PgPool},
+    markdown,
+    models::user::SteamId,
+};
+use chrono::naive::NaiveDateTime;
+use futures::{stream::Stream, Future};
+
+// A guide written on the site, as opposed to those compiled from
+// `markdown/guides/` by build.rs
+from_row! {
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Guide {
+        pub id: i32,
+        pub user_id: i32,
+        pub title: String,
+        pub body: String,
+        pub published: bool,
+        pub created_at: NaiveDateTime,
+        pub updated_at: NaiveDateTime,
+        pub author_steam_id: SteamId,
+    }
+}
+
+impl Guide {
+    pub fn query(id: i32, pool: &PgPool) -> impl Future<Item = Option<Self>, Error = app::Error> {
+        pool.connection().and_then(move |mut conn| {
+            let conn = &mut *conn;
+            conn.query_as(|q| &q.guide_single, &[&id])
+                .into_future()
+                .map(|(guide, _)| guide)
+                .map_err(|(e, _)| app::Error::from(e))
+        })
+    }
+
+    // Published guides, along with the drafts of `user_id` if given
+    pub fn query_multiple(
+        user_id: Option<i32>,
+        pool: &PgPool,
+    ) -> impl Future<Item = Vec<Self>, Error = app::Error> {
+        pool.connection()
+            .from_err::<app::Error>()
+            .and_then(move |mut conn| {
+                let conn = &mut *conn;
+                let published = conn
+                    .query_as(|q| &q.guide_multiple_published, &[])
+                    .collect();
+                let own = match user_id {
+                    Some(user_id) => futures::future::Either::A(
+                        conn.query_as(|q| &q.guide_multiple_by_user, &[&user_id])
+                            .collect(),
+                    ),
+                    None => futures::future::Either::B(futures::future::ok(Vec::new())),
+                };
+
+                published.join(own).from_err()
+            })
+            .map(|(published, own)| {
+                let mut guides = own.into_iter().filter(|g| !g.published).collect::<Vec<_>>();
+                guides.extend(published);
+                guides
+            })
+    }
+
+    // The body as sanitized html, safe to include in a page
+    pub fn render_body(&self) -> String {
+        ammonia::clean(&markdown::to_html(&self.body))
+    }
+}
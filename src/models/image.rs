@@ -2,13 +2,15 @@ use crate::app::{self, PgConn};
 use chrono::naive::NaiveDateTime;
 use futures::{stream::Stream, Future};
 
-#[derive(Debug, Serialize)]
-pub struct Image {
-    pub id: i32,
-    pub url: String,
-    pub loadout_id: i32,
-    pub position: i32,
-    pub created_at: NaiveDateTime,
+from_row! {
+    #[derive(Debug, Serialize)]
+    pub struct Image {
+        pub id: i32,
+        pub url: String,
+        pub loadout_id: i32,
+        pub position: i32,
+        pub created_at: NaiveDateTime,
+    }
 }
 
 impl Image {
@@ -17,17 +19,7 @@ impl Image {
         conn: &mut PgConn,
     ) -> impl Future<Item = Vec<Self>, Error = app::Error> {
         let conn = &mut *conn;
-        conn.client
-            .query(&conn.queries.get_image_by_id, &[&loadout_id])
-            .and_then(|row| {
-                Ok(Image {
-                    id: row.get(0),
-                    url: row.get(1),
-                    loadout_id: row.get(2),
-                    position: row.get(3),
-                    created_at: row.get(4),
-                })
-            })
+        conn.query_as(|q| &q.get_image_by_id, &[&loadout_id])
             .from_err()
             .collect()
     }
@@ -1,113 +1,165 @@
 use crate::{
     app::{self, PgConn, PgPool},
+    cache::Cache,
+    catalog::Weapon,
+    cost::{self, PatchStatus},
+    equipment::Equipment,
     models::{user::SteamId, User},
 };
 use chrono::naive::NaiveDateTime;
-use futures::{future::Future, stream::Stream};
-
-#[derive(Debug, Clone, Serialize)]
-pub struct LoadoutSingle {
-    pub id: i32,
-    pub user_id: i32,
-    pub name: String,
-    pub data: String,
-    pub created_at: NaiveDateTime,
-    pub like_count: i64,
-    pub has_liked: bool,
+use futures::{
+    future::{self, Either, Future},
+    stream::Stream,
+};
+
+from_row! {
+    #[derive(Debug, Clone, Serialize)]
+    pub struct LoadoutSingle {
+        pub id: i32,
+        pub user_id: i32,
+        pub name: String,
+        pub data: String,
+        pub game_version: String,
+        pub created_at: NaiveDateTime,
+        pub like_count: i64,
+        // Always false for anonymous users
+        pub has_liked: bool,
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct LoadoutMultiple {
-    pub id: i32,
-    pub user_id: i32,
-    pub name: String,
-    pub data: String,
-    pub created_at: NaiveDateTime,
-    pub like_count: i64,
-    pub has_liked: bool,
-    pub main_image_url: String,
-    pub user_steam_id: SteamId,
+from_row! {
+    #[derive(Debug, Clone, Serialize)]
+    pub struct LoadoutMultiple {
+        pub id: i32,
+        pub user_id: i32,
+        pub name: String,
+        pub data: String,
+        pub game_version: String,
+        pub created_at: NaiveDateTime,
+        pub like_count: i64,
+        pub has_liked: bool,
+        pub main_image_url: String,
+        pub user_steam_id: SteamId,
+    }
 }
 
 impl LoadoutMultiple {
+    pub fn patch_status(&self) -> PatchStatus {
+        cost::patch_status(&self.data, &self.game_version)
+    }
+
     pub fn query(
         user: Option<User>,
         pool: &PgPool,
     ) -> impl Future<Item = Vec<Self>, Error = app::Error> {
-        let with_user = user.is_some();
+        let user_id = user.map(|u| u.id);
 
         // f3d5pzxulmlbpanpf5sc
-        pool.connection()
-            .from_err::<app::Error>()
-            .and_then(move |mut conn| {
-                let conn = &mut *conn;
-                let query = if with_user {
-                    conn.client.query(
-                        &conn.queries.loadout_multiple_with_user,
-                        &[&user.unwrap().id],
-                    )
-                } else {
-                    conn.client
-                        .query(&conn.queries.loadout_multiple_without_user, &[])
-                };
-
-                query.collect().from_err()
-            })
-            .and_then(move |rows| {
-                Ok(rows
-                    .into_iter()
-                    .map(|row| LoadoutMultiple {
-                        id: row.get(0),
-                        user_id: row.get(1),
-                        name: row.get(2),
-                        data: row.get(3),
-                        created_at: row.get(4),
-                        like_count: row.get(5),
-                        user_steam_id: row.get(6),
-                        main_image_url: row.get(7),
-                        has_liked: if with_user { row.get(8) } else { false },
-                    })
-                    .collect())
-            })
+        pool.connection().and_then(move |mut conn| {
+            conn.query_as(|q| &q.loadout_multiple, &[&user_id])
+                .collect()
+                .from_err()
+        })
+    }
+
+    // The loadouts with the given ids, in no particular order. Missing ones are
+    // left out.
+    pub fn query_by_ids(
+        ids: &'static [i32],
+        user: Option<User>,
+        pool: &PgPool,
+    ) -> impl Future<Item = Vec<Self>, Error = app::Error> {
+        let user_id = user.map(|u| u.id);
+
+        pool.connection().and_then(move |mut conn| {
+            conn.query_as(|q| &q.loadouts_by_ids, &[&user_id, &ids])
+                .collect()
+                .from_err()
+        })
+    }
+
+    // The `limit` most liked loadouts which use `weapon`
+    pub fn query_using_weapon(
+        weapon: &Weapon,
+        limit: u64,
+        user: Option<User>,
+        pool: &PgPool,
+    ) -> impl Future<Item = Vec<Self>, Error = app::Error> {
+        let user_id = user.map(|u| u.id);
+        let weapon_id = weapon.id;
+
+        // Narrows things down to loadouts with an item of the weapon's id,
+        // which could also be a wearable. Whether they actually use it is
+        // checked by parsing them, stopping once there are enough.
+        let pattern = format!(r"\(ID={}[,)]", weapon_id);
+
+        pool.connection().and_then(move |mut conn| {
+            conn.query_as(|q| &q.loadouts_matching, &[&user_id, &pattern])
+                .filter(move |loadout: &Self| {
+                    Equipment::parse(&loadout.data)
+                        .map(|e| e.weapons.contains(&weapon_id))
+                        .unwrap_or(false)
+                })
+                .take(limit)
+                .collect()
+                .from_err()
+        })
+    }
+
+    // The loadouts created by `owner`, as seen by `user`
+    pub fn query_by_user(
+        owner_id: i32,
+        user: Option<User>,
+        pool: &PgPool,
+    ) -> impl Future<Item = Vec<Self>, Error = app::Error> {
+        let user_id = user.map(|u| u.id);
+
+        pool.connection().and_then(move |mut conn| {
+            conn.query_as(|q| &q.loadouts_by_user, &[&user_id, &owner_id])
+                .collect()
+                .from_err()
+        })
     }
 }
 
 impl LoadoutSingle {
+    pub fn patch_status(&self) -> PatchStatus {
+        cost::patch_status(&self.data, &self.game_version)
+    }
+
     pub fn query(
         loadout_id: i32,
         user: Option<User>,
         conn: &mut PgConn,
     ) -> impl Future<Item = Option<Self>, Error = app::Error> {
-        let with_user = user.is_some();
+        let user_id = user.map(|u| u.id);
 
         // f3d5pzxulmlbpanpf5sc
-        let conn = &mut *conn;
-        let query = if with_user {
-            let user_id = user.map(|u| u.id).unwrap_or(0);
-            conn.client.query(
-                &conn.queries.loadout_single_with_user,
-                &[&user_id, &loadout_id],
-            )
-        } else {
-            conn.client
-                .query(&conn.queries.loadout_single_without_user, &[&loadout_id])
-        };
-
-        query
+        conn.query_as(|q| &q.loadout_single, &[&user_id, &loadout_id])
             .into_future()
-            .map(|(r, _)| r)
-            .map_err(|(e, _)| e)
-            .from_err()
-            .and_then(move |row| {
-                Ok(row.map(|row| LoadoutSingle {
-                    id: row.get(0),
-                    user_id: row.get(1),
-                    name: row.get(2),
-                    data: row.get(3),
-                    created_at: row.get(4),
-                    like_count: row.get(5),
-                    has_liked: if with_user { row.get(6) } else { false },
-                }))
-            })
+            .map(|(loadout, _)| loadout)
+            .map_err(|(e, _)| app::Error::from(e))
+    }
+
+    // Like `query`, but using `cache` for loadouts looked up recently
+    pub fn query_cached(
+        loadout_id: i32,
+        user: Option<User>,
+        conn: &mut PgConn,
+        cache: &Cache<(i32, Option<i32>), Self>,
+    ) -> impl Future<Item = Option<Self>, Error = app::Error> {
+        let key = (loadout_id, user.as_ref().map(|u| u.id));
+
+        if let Some(loadout) = cache.get(&key) {
+            return Either::A(future::ok(Some(loadout)));
+        }
+
+        let cache = cache.clone();
+        Either::B(Self::query(loadout_id, user, conn).map(move |loadout| {
+            if let Some(loadout) = &loadout {
+                cache.insert(key, loadout.clone());
+            }
+            loadout
+        }))
     }
 }
@@ -6,3 +6,9 @@ pub use loadout::{LoadoutMultiple, LoadoutSingle};
 
 pub mod image;
 pub use image::Image;
+
+pub mod guide;
+pub use guide::Guide;
+
+pub mod revision;
+pub use revision::{GuideRevision, RevisionStatus};
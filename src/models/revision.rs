@@ -0,0 +1,85 @@
+use crate::{
+    app::{self, PgPool},
+    models::user::SteamId,
+};
+use chrono::naive::NaiveDateTime;
+use futures::{stream::Stream, Future};
+use std::fmt;
+use tokio_postgres::types::{FromSql, Type};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RevisionStatus {
+    // Suggested by someone other than the author, waiting for review
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+// A version of a community guide. Edits by the author are accepted straight
+// away, while edits by anyone else are kept as pending suggestions.
+from_row! {
+    #[derive(Debug, Clone, Serialize)]
+    pub struct GuideRevision {
+        pub id: i32,
+        pub guide_id: i32,
+        pub user_id: i32,
+        pub title: String,
+        pub body: String,
+        pub status: RevisionStatus,
+        pub created_at: NaiveDateTime,
+        pub editor_steam_id: SteamId,
+    }
+}
+
+impl RevisionStatus {
+    fn from_db(status: &str) -> Self {
+        match status {
+            "accepted" => RevisionStatus::Accepted,
+            "rejected" => RevisionStatus::Rejected,
+            _ => RevisionStatus::Pending,
+        }
+    }
+}
+
+impl<'a> FromSql<'a> for RevisionStatus {
+    fn from_sql(
+        ty: &Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        <&str>::from_sql(ty, raw).map(RevisionStatus::from_db)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <&str>::accepts(ty)
+    }
+}
+
+impl GuideRevision {
+    // Every revision of a guide, oldest first
+    pub fn query_for_guide(
+        guide_id: i32,
+        pool: &PgPool,
+    ) -> impl Future<Item = Vec<Self>, Error = app::Error> {
+        pool.connection().and_then(move |mut conn| {
+            let conn = &mut *conn;
+            conn.query_as(|q| &q.guide_revisions, &[&guide_id])
+                .collect()
+                .from_err()
+        })
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status == RevisionStatus::Pending
+    }
+}
+
+impl fmt::Display for RevisionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RevisionStatus::Pending => write!(f, "Pending"),
+            RevisionStatus::Accepted => write!(f, "Accepted"),
+            RevisionStatus::Rejected => write!(f, "Rejected"),
+        }
+    }
+}
@@ -1,4 +1,7 @@
-use crate::app::{self, PgPool, State};
+use crate::{
+    app::{self, PgPool, State},
+    logging,
+};
 use actix_web::{dev::Payload, middleware::identity::Identity, web, FromRequest, HttpRequest};
 use futures::{stream::Stream, Future, IntoFuture};
 use std::str::FromStr;
@@ -62,10 +65,14 @@ impl std::fmt::Display for SteamId {
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct User {
-    pub id: i32,
-    pub steam_id: SteamId,
+from_row! {
+    #[derive(Debug, Clone, Serialize)]
+    pub struct User {
+        pub id: i32,
+        pub steam_id: SteamId,
+        pub is_moderator: bool,
+        pub persona_name: Option<String>,
+    }
 }
 
 impl User {
@@ -73,22 +80,13 @@ impl User {
         steam_id: SteamId,
         pool: &PgPool,
     ) -> impl Future<Item = Option<User>, Error = app::Error> {
-        pool.connection()
-            .from_err()
-            .and_then(move |mut conn| {
-                let conn = &mut *conn;
-                conn.client
-                    .query(&conn.queries.get_user_by_id, &[&steam_id.as_i64()])
-                    .into_future()
-                    .map(|(r, _)| r)
-                    .map_err(|(e, _)| app::Error::from(e))
-            })
-            .and_then(|row| {
-                Ok(row.map(|row| User {
-                    id: row.get(0),
-                    steam_id: row.get(1),
-                }))
-            })
+        pool.connection().and_then(move |mut conn| {
+            let conn = &mut *conn;
+            conn.query_as(|q| &q.get_user_by_id, &[&steam_id.as_i64()])
+                .into_future()
+                .map(|(user, _)| user)
+                .map_err(|(e, _)| app::Error::from(e))
+        })
     }
 }
 
@@ -114,12 +112,17 @@ impl FromRequest for User {
 
     fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
         let state = web::Data::<State>::from_request(req, payload).expect("can't get app state");
+        let req = req.clone();
 
         Box::new(
-            SteamId::from_request(req, payload)
+            SteamId::from_request(&req, payload)
                 .into_future()
                 .and_then(move |steam_id| User::get_by_steam_id(steam_id, state.get_db()))
-                .and_then(|user| user.ok_or(app::Error::Unauthorized)),
+                .and_then(|user| user.ok_or(app::Error::Unauthorized))
+                .map(move |user| {
+                    logging::record_user(&req, user.id);
+                    user
+                }),
         )
     }
 }
@@ -0,0 +1,435 @@
+// Rate limiting for routes which are expensive or easy to abuse. Each route in
+// `LIMITS` gets a token bucket per client IP, and optionally one per logged in
+// user. Requests over budget get a 429 with `Retry-After`.
+//
+// IPv6 clients are limited per /64, as that's usually what one client gets,
+// and addresses within it are free to change. Users are told apart by their
+// steam id, which is what the identity cookie holds. It identifies them just
+// as well as `User.id`, without having to look them up for every request.
+
+use crate::{app, https};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    middleware::identity::RequestIdentity,
+    ResponseError,
+};
+use futures::{
+    future::{ok, Either, FutureResult},
+    Poll,
+    Stream,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio_timer::Interval;
+
+// Full buckets are no different to new ones, so they're swept away this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Past this many buckets, the least recently used are dropped to make room,
+// `EVICT_BATCH` at a time so that it doesn't happen for every new client
+const MAX_BUCKETS: usize = 10_000;
+const EVICT_BATCH: usize = MAX_BUCKETS / 10;
+
+// `requests` can be made at once, and they're allowed back at an even rate
+// over `per`
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    requests: u32,
+    per: Duration,
+}
+
+fn per_minute(requests: u32) -> Budget {
+    Budget {
+        requests,
+        per: Duration::from_secs(60),
+    }
+}
+
+struct Limit {
+    method: Method,
+    // A route as passed to `App::route`, with `{...}` matching any segment
+    path: &'static str,
+    per_ip: Budget,
+    per_user: Option<Budget>,
+}
+
+lazy_static::lazy_static! {
+    static ref LIMITS: Vec<Limit> = vec![
+        // Every callback makes a request to steam
+        Limit {
+            method: Method::GET,
+            path: "/auth/callback",
+            per_ip: per_minute(10),
+            per_user: None,
+        },
+        Limit {
+            method: Method::GET,
+            path: "/auth/login",
+            per_ip: per_minute(20),
+            per_user: None,
+        },
+        Limit {
+            method: Method::POST,
+            path: "/loadouts/create",
+            per_ip: per_minute(10),
+            per_user: Some(per_minute(5)),
+        },
+        Limit {
+            method: Method::POST,
+            path: "/loadouts/{id}/edit",
+            per_ip: per_minute(30),
+            per_user: Some(per_minute(20)),
+        },
+        Limit {
+            method: Method::POST,
+            path: "/loadouts/{id}/delete",
+            per_ip: per_minute(30),
+            per_user: Some(per_minute(20)),
+        },
+        Limit {
+            method: Method::POST,
+            path: "/loadouts/{id}/like",
+            per_ip: per_minute(60),
+            per_user: Some(per_minute(30)),
+        },
+        Limit {
+            method: Method::POST,
+            path: "/guides/community/new",
+            per_ip: per_minute(10),
+            per_user: Some(per_minute(5)),
+        },
+        Limit {
+            method: Method::POST,
+            path: "/guides/community/{id}/edit",
+            per_ip: per_minute(30),
+            per_user: Some(per_minute(20)),
+        },
+        Limit {
+            method: Method::GET,
+            path: "/search",
+            per_ip: per_minute(60),
+            per_user: None,
+        },
+        Limit {
+            method: Method::GET,
+            path: "/api/v1/search",
+            per_ip: per_minute(60),
+            per_user: None,
+        },
+        Limit {
+            method: Method::POST,
+            path: "/api/v1/loadouts/validate",
+            per_ip: per_minute(60),
+            per_user: None,
+        },
+    ];
+
+    static ref BUCKETS: Mutex<HashMap<(usize, Key), Bucket>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    User(String),
+}
+
+impl Key {
+    fn ip(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Key::Ip(ip),
+            IpAddr::V6(ip) => {
+                let network = u128::from(ip) & (!0 << 64);
+                Key::Ip(IpAddr::V6(Ipv6Addr::from(network)))
+            }
+        }
+    }
+}
+
+struct Bucket {
+    budget: Budget,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Budget {
+    // Tokens added back per second
+    fn rate(self) -> f64 {
+        let per = self.per.as_secs() as f64 + f64::from(self.per.subsec_millis()) / 1e3;
+        f64::from(self.requests) / per
+    }
+}
+
+impl Bucket {
+    fn new(budget: Budget) -> Self {
+        Self {
+            budget,
+            tokens: f64::from(budget.requests),
+            updated: Instant::now(),
+        }
+    }
+
+    // The tokens there are now, counting those added back since it was used
+    fn tokens(&self) -> f64 {
+        let elapsed = self.updated.elapsed();
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1e3;
+
+        let refilled = self.tokens + elapsed * self.budget.rate();
+        refilled.min(f64::from(self.budget.requests))
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens() >= f64::from(self.budget.requests)
+    }
+
+    // How many seconds until there's a token to take, if there isn't one now
+    fn wait(&self) -> Option<u64> {
+        let tokens = self.tokens();
+
+        if tokens >= 1.0 {
+            None
+        } else {
+            Some(((1.0 - tokens) / self.budget.rate()).ceil() as u64)
+        }
+    }
+
+    // Take a token, or say how many seconds until there's one to take
+    fn take(&mut self) -> Result<(), u64> {
+        if let Some(wait) = self.wait() {
+            return Err(wait);
+        }
+
+        self.tokens = self.tokens() - 1.0;
+        self.updated = Instant::now();
+        Ok(())
+    }
+}
+
+impl Limit {
+    fn matches(&self, req: &ServiceRequest) -> bool {
+        if *req.method() != self.method {
+            return false;
+        }
+
+        let mut pattern = self.path.split('/');
+        let mut path = req.path().split('/');
+
+        loop {
+            match (pattern.next(), path.next()) {
+                (None, None) => return true,
+                (Some(p), Some(s)) if p == s || (p.starts_with('{') && !s.is_empty()) => {}
+                _ => return false,
+            }
+        }
+    }
+}
+
+// Take a token from each of the request's buckets for the limit at `index`.
+// Tokens are only taken if every bucket has one, so that a request turned
+// away by one bucket doesn't use up another.
+fn check(index: usize, limit: &Limit, req: &ServiceRequest) -> Result<(), u64> {
+    let mut keys = Vec::new();
+
+    if let Some(ip) = https::client_ip(req) {
+        keys.push(((index, Key::ip(ip)), limit.per_ip));
+    }
+    if let (Some(user), Some(budget)) = (req.get_identity(), limit.per_user) {
+        keys.push(((index, Key::User(user)), budget));
+    }
+
+    let mut buckets = BUCKETS.lock().unwrap();
+
+    for (key, budget) in &keys {
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            evict_least_recent(&mut buckets);
+        }
+
+        buckets
+            .entry(key.clone())
+            .or_insert_with(|| Bucket::new(*budget));
+    }
+
+    // The longest wait, as the request needs a token from every bucket
+    let wait = keys
+        .iter()
+        .filter_map(|(key, _)| buckets.get(key).and_then(Bucket::wait))
+        .max();
+    if let Some(wait) = wait {
+        return Err(wait);
+    }
+
+    for (key, _) in &keys {
+        if let Some(bucket) = buckets.get_mut(key) {
+            bucket.take()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn evict_least_recent(buckets: &mut HashMap<(usize, Key), Bucket>) {
+    let mut by_age = buckets
+        .iter()
+        .map(|(key, bucket)| (bucket.updated, key.clone()))
+        .collect::<Vec<_>>();
+    by_age.sort_by_key(|(updated, _)| *updated);
+
+    for (_, key) in by_age.into_iter().take(EVICT_BATCH) {
+        buckets.remove(&key);
+    }
+}
+
+// Drop full buckets every `SWEEP_INTERVAL`, so that clients which have gone
+// away don't take up space
+pub fn sweep_periodically() {
+    actix_rt::spawn(
+        Interval::new(Instant::now() + SWEEP_INTERVAL, SWEEP_INTERVAL)
+            .map_err(|e| error!("rate limit sweep timer failed: {}", e))
+            .for_each(|_| {
+                BUCKETS
+                    .lock()
+                    .unwrap()
+                    .retain(|_, bucket| !bucket.is_full());
+                Ok(())
+            }),
+    );
+}
+
+pub struct RateLimit;
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = FutureResult<Self::Transform, Self::InitError>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware { service })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = Either<S::Future, FutureResult<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.service.poll_ready()
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let limited = LIMITS
+            .iter()
+            .enumerate()
+            .find(|(_, limit)| limit.matches(&req))
+            .map(|(index, limit)| check(index, limit, &req));
+
+        match limited {
+            Some(Err(retry_after)) => {
+                let err = app::Error::RateLimited(retry_after);
+                Either::B(ok(req.into_response(err.error_response().into_body())))
+            }
+            _ => Either::A(self.service.call(req)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn limit(method: Method, path: &'static str) -> Limit {
+        Limit {
+            method,
+            path,
+            per_ip: per_minute(10),
+            per_user: None,
+        }
+    }
+
+    fn request(method: Method, path: &str) -> ServiceRequest {
+        TestRequest::with_uri(path).method(method).to_srv_request()
+    }
+
+    #[test]
+    fn matches_method_and_path() {
+        let limit = limit(Method::POST, "/loadouts/create");
+
+        assert!(limit.matches(&request(Method::POST, "/loadouts/create")));
+        assert!(!limit.matches(&request(Method::GET, "/loadouts/create")));
+        assert!(!limit.matches(&request(Method::POST, "/loadouts/create/extra")));
+        assert!(!limit.matches(&request(Method::POST, "/loadouts")));
+    }
+
+    #[test]
+    fn parameters_match_any_segment_but_an_empty_one() {
+        let limit = limit(Method::POST, "/loadouts/{id}/edit");
+
+        assert!(limit.matches(&request(Method::POST, "/loadouts/5/edit")));
+        assert!(!limit.matches(&request(Method::POST, "/loadouts//edit")));
+        assert!(!limit.matches(&request(Method::POST, "/loadouts/5/6/edit")));
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_64() {
+        let a = "2001:db8:1:2:aaaa::1".parse().unwrap();
+        let b = "2001:db8:1:2:bbbb::2".parse().unwrap();
+        let other = "2001:db8:1:3::1".parse().unwrap();
+
+        assert_eq!(Key::ip(a), Key::ip(b));
+        assert_eq!(Key::ip(a), Key::Ip("2001:db8:1:2::".parse().unwrap()));
+        assert_ne!(Key::ip(a), Key::ip(other));
+    }
+
+    #[test]
+    fn ipv4_clients_are_kept_apart() {
+        let a = "192.0.2.1".parse().unwrap();
+        let b = "192.0.2.2".parse().unwrap();
+
+        assert_eq!(Key::ip(a), Key::Ip(a));
+        assert_ne!(Key::ip(a), Key::ip(b));
+    }
+
+    #[test]
+    fn take_rounds_retry_after_up() {
+        // A token every 6 seconds
+        let mut bucket = Bucket::new(per_minute(10));
+        for _ in 0..10 {
+            assert_eq!(bucket.take(), Ok(()));
+        }
+        assert_eq!(bucket.take(), Err(6));
+
+        // A token every 8.57 seconds
+        let mut bucket = Bucket::new(per_minute(7));
+        for _ in 0..7 {
+            assert_eq!(bucket.take(), Ok(()));
+        }
+        assert_eq!(bucket.take(), Err(9));
+
+        // Half a token left, so half as long to wait
+        bucket.tokens = 0.5;
+        assert_eq!(bucket.take(), Err(5));
+    }
+}
@@ -1,5 +1,14 @@
-use crate::app;
-use actix_web::web::Json;
+use crate::{
+    app::{self, State},
+    cost::{self, CostReport},
+    routes::search::SearchQuery,
+    search::{self, SearchResults},
+};
+use actix_web::{
+    web::{self, Json},
+    HttpRequest,
+};
+use futures::Future;
 
 #[derive(Serialize)]
 pub struct TestResponse {
@@ -15,3 +24,20 @@ pub fn test() -> Result<Json<TestResponse>, app::Error> {
         baz: false,
     }))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct ValidateLoadout {
+    data: String,
+}
+
+pub fn validate_loadout(req: Json<ValidateLoadout>) -> Result<Json<CostReport>, app::Error> {
+    Ok(Json(cost::evaluate(&req.data)))
+}
+
+pub fn search(
+    req: HttpRequest,
+    query: web::Query<SearchQuery>,
+    state: web::Data<State>,
+) -> impl Future<Item = Json<SearchResults>, Error = app::Error> {
+    search::search(&query.q, state.read_db(&req)).map(Json)
+}
@@ -0,0 +1,43 @@
+use crate::{
+    app,
+    assets::{self, Lookup},
+};
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use std::fs;
+
+// Fingerprinted urls change whenever the file does, so they can be cached forever
+const CACHE_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const CACHE_REVALIDATE: &str = "no-cache";
+
+pub fn serve(name: web::Path<String>, req: HttpRequest) -> Result<HttpResponse, app::Error> {
+    match assets::lookup(&name) {
+        Lookup::Immutable(asset) => {
+            let accept_encoding = req
+                .headers()
+                .get(header::ACCEPT_ENCODING)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or_default();
+            let (encoding, data) = asset.encoded(accept_encoding);
+
+            let mut res = HttpResponse::Ok();
+            res.content_type(asset.content_type)
+                .header(header::CACHE_CONTROL, CACHE_IMMUTABLE)
+                .header(header::VARY, "Accept-Encoding");
+
+            if let Some(encoding) = encoding.header_value() {
+                res.header(header::CONTENT_ENCODING, encoding);
+            }
+
+            Ok(res.body(data))
+        }
+        Lookup::Mutable { path, content_type } => {
+            let data = fs::read(path).map_err(|_| app::Error::NotFound)?;
+
+            Ok(HttpResponse::Ok()
+                .content_type(content_type)
+                .header(header::CACHE_CONTROL, CACHE_REVALIDATE)
+                .body(data))
+        }
+        Lookup::NotFound => Err(app::Error::NotFound),
+    }
+}
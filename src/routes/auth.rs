@@ -1,9 +1,15 @@
 use crate::{
     app::{self, State},
+    metrics,
     models::user::SteamId,
+    steam,
 };
 use actix_web::{middleware::identity::Identity, web, HttpRequest, HttpResponse};
-use futures::Future;
+use futures::{
+    future::{self, Either},
+    Future,
+    Stream,
+};
 
 pub fn login(state: web::Data<State>) -> Result<HttpResponse, app::Error> {
     Ok(HttpResponse::Found()
@@ -24,20 +30,29 @@ pub fn callback(
     state: web::Data<State>,
 ) -> impl Future<Item = HttpResponse, Error = app::Error> {
     steam_auth::Verifier::make_verify_request_async(&state.reqwest, req.query_string().to_owned())
+        .then(|res| {
+            let result = if res.is_ok() { "success" } else { "failure" };
+            metrics::STEAM_AUTH.with_label_values(&[result]).inc();
+            res
+        })
         .map(SteamId::from)
         .map_err(app::Error::SteamAuth)
         .and_then(move |steam_id| {
-            state
-                .get_db()
-                .connection()
-                .from_err()
-                .and_then(move |mut conn| {
-                    let conn = &mut *conn;
-                    conn.client
-                        .execute(&conn.queries.post_login_insert_user, &[&steam_id.as_i64()])
+            stale_persona_name(&state, steam_id).and_then(move |persona_name| {
+                state
+                    .get_db()
+                    .connection()
+                    .from_err()
+                    .and_then(move |mut conn| {
+                        let conn = &mut *conn;
+                        conn.execute(
+                            |q| &q.post_login_insert_user,
+                            &[&steam_id.as_i64(), &persona_name],
+                        )
                         .map(move |_| steam_id)
                         .from_err()
-                })
+                    })
+            })
         })
         .and_then(move |steam_id| {
             debug!("Verified user {}", steam_id);
@@ -45,3 +60,33 @@ pub fn callback(
             Ok(HttpResponse::Found().header("Location", "/").finish())
         })
 }
+
+// The user's persona name from steam, if the one we have is missing or out of
+// date. Otherwise `None`, which leaves the stored one alone, so that most
+// logins don't wait on another request to steam.
+fn stale_persona_name(
+    state: &State,
+    steam_id: SteamId,
+) -> impl Future<Item = Option<String>, Error = app::Error> {
+    let client = state.reqwest.clone();
+
+    state
+        .get_db()
+        .connection()
+        .and_then(move |mut conn| {
+            conn.query(|q| &q.user_persona_name_stale, &[&steam_id.as_i64()])
+                .into_future()
+                .map_err(|(e, _)| app::Error::from(e))
+                .and_then(|(row, _)| {
+                    row.map(|row| row.get::<_, bool>(0))
+                        .ok_or(app::Error::DbNothingReturned)
+                })
+        })
+        .and_then(move |stale| {
+            if stale {
+                Either::A(steam::persona_name(&client, steam_id))
+            } else {
+                Either::B(future::ok(None))
+            }
+        })
+}
@@ -0,0 +1,74 @@
+use crate::{
+    app::{self, ActiveLink, State, TmplBase},
+    catalog::{self, Weapon},
+    models::{LoadoutMultiple, User},
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use futures::{
+    future::{self, Either},
+    Future,
+    IntoFuture,
+};
+
+// Number of loadouts shown on each catalog page
+const TOP_LOADOUTS: u64 = 10;
+
+#[derive(Template)]
+#[template(path = "catalog/weapon.html")]
+struct WeaponPage {
+    base: TmplBase,
+    weapon: Weapon,
+    loadouts: Vec<LoadoutMultiple>,
+}
+
+pub fn weapon(
+    req: HttpRequest,
+    name: web::Path<String>,
+    user: Option<User>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    catalog::current()
+        .weapon(&name)
+        .cloned()
+        .ok_or(app::Error::NotFound)
+        .into_future()
+        .and_then(move |weapon| {
+            let cache_key = if user.is_none() {
+                Some(format!("/catalog/weapons/{}", weapon.slug()))
+            } else {
+                None
+            };
+
+            if let Some(page) = state.cached_page(cache_key.as_ref().map(String::as_str)) {
+                return Either::A(future::ok(page));
+            }
+
+            Either::B(
+                LoadoutMultiple::query_using_weapon(
+                    &weapon,
+                    TOP_LOADOUTS,
+                    user.clone(),
+                    state.cache_db(&req),
+                )
+                .and_then(move |loadouts| {
+                    let slug = weapon.slug();
+
+                    state.render_cached(
+                        cache_key,
+                        WeaponPage {
+                            base: TmplBase::new(user, ActiveLink::None)
+                                .with_title(weapon.name.clone())
+                                .with_description(format!(
+                                    "Stats for the {} and the most liked loadouts using it.",
+                                    weapon.name
+                                ))
+                                .with_canonical(&format!("/catalog/weapons/{}", slug)),
+                            weapon,
+                            loadouts,
+                        },
+                    )
+                }),
+            )
+        })
+}
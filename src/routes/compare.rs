@@ -0,0 +1,260 @@
+use crate::{
+    app::{self, ActiveLink, State, TmplBase},
+    catalog::{self, ArmorSlot, Weapon},
+    cost::{self, CostReport},
+    equipment::Equipment,
+    models::{LoadoutSingle, User},
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use futures::{future, Future, IntoFuture};
+
+const MIN_LOADOUTS: usize = 2;
+const MAX_LOADOUTS: usize = 4;
+
+#[derive(Debug, Deserialize)]
+pub struct CompareQuery {
+    // Comma separated loadout ids, e.g `1,2,3`
+    ids: String,
+}
+
+struct Column {
+    loadout: LoadoutSingle,
+    cost: CostReport,
+}
+
+struct Cell {
+    text: String,
+    // Extra detail shown under the cell, e.g weapon stat deltas
+    note: Option<String>,
+}
+
+struct Row {
+    slot: String,
+    cells: Vec<Cell>,
+    differs: bool,
+}
+
+impl Cell {
+    fn new<S: Into<String>>(text: S) -> Self {
+        Self {
+            text: text.into(),
+            note: None,
+        }
+    }
+}
+
+impl Row {
+    fn new<S: Into<String>>(slot: S, cells: Vec<Cell>) -> Self {
+        Self {
+            slot: slot.into(),
+            differs: cells.windows(2).any(|w| w[0].text != w[1].text),
+            cells,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "loadouts/compare.html")]
+struct Compare {
+    base: TmplBase,
+    columns: Vec<Column>,
+    rows: Vec<Row>,
+    shared_perks: Vec<String>,
+}
+
+pub fn compare(
+    req: HttpRequest,
+    query: web::Query<CompareQuery>,
+    user: Option<User>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    parse_ids(&query.ids).into_future().and_then(move |ids| {
+        state
+            .cache_db(&req)
+            .connection()
+            .from_err()
+            .and_then(move |mut conn| {
+                let queries = ids
+                    .into_iter()
+                    .map(|id| {
+                        LoadoutSingle::query_cached(
+                            id,
+                            user.clone(),
+                            &mut conn,
+                            state.loadout_cache(),
+                        )
+                        .and_then(|ldt| ldt.ok_or(app::Error::NotFound))
+                    })
+                    .collect::<Vec<_>>();
+
+                // Run queries in parallel
+                future::join_all(queries).and_then(move |loadouts| {
+                    let equipment = loadouts
+                        .iter()
+                        .map(|l| Equipment::parse(&l.data).unwrap_or_default())
+                        .collect::<Vec<_>>();
+
+                    let columns = loadouts
+                        .into_iter()
+                        .map(|loadout| Column {
+                            cost: cost::evaluate(&loadout.data),
+                            loadout,
+                        })
+                        .collect::<Vec<_>>();
+
+                    State::render(Compare {
+                        base: TmplBase::new(user, ActiveLink::Loadouts)
+                            .with_title("Compare loadouts"),
+                        rows: build_rows(&columns, &equipment),
+                        shared_perks: shared_perks(&equipment),
+                        columns,
+                    })
+                })
+            })
+    })
+}
+
+fn parse_ids(ids: &str) -> Result<Vec<i32>, app::Error> {
+    let mut out = Vec::new();
+
+    for id in ids.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let id = id
+            .parse::<i32>()
+            .map_err(|_| app::Error::BadRequest(format!("invalid loadout id '{}'", id)))?;
+
+        if !out.contains(&id) {
+            out.push(id);
+        }
+    }
+
+    if out.len() < MIN_LOADOUTS || out.len() > MAX_LOADOUTS {
+        return Err(app::Error::BadRequest(format!(
+            "can only compare between {} and {} loadouts",
+            MIN_LOADOUTS, MAX_LOADOUTS
+        )));
+    }
+
+    Ok(out)
+}
+
+fn build_rows(columns: &[Column], equipment: &[Equipment]) -> Vec<Row> {
+    let catalog = catalog::current();
+    let mut rows = Vec::new();
+
+    rows.push(Row::new(
+        "Points",
+        columns
+            .iter()
+            .map(|c| Cell::new(format!("{} / {}", c.cost.total, c.cost.budget)))
+            .collect(),
+    ));
+
+    // Weapons are compared slot by slot, with stat deltas against the first loadout
+    let weapon_slots = equipment.iter().map(|e| e.weapons.len()).max().unwrap_or(0);
+    for slot in 0..weapon_slots {
+        let baseline = equipment[0]
+            .weapons
+            .get(slot)
+            .and_then(|&id| catalog.weapon_by_id(id));
+
+        let cells = equipment
+            .iter()
+            .map(|e| match e.weapons.get(slot) {
+                Some(&id) => match catalog.weapon_by_id(id) {
+                    Some(weapon) => Cell {
+                        text: weapon.name.clone(),
+                        note: baseline
+                            .filter(|b| b.name != weapon.name)
+                            .map(|b| stat_delta(b, weapon)),
+                    },
+                    None => Cell::new(unknown(id)),
+                },
+                None => Cell::new("-"),
+            })
+            .collect();
+
+        rows.push(Row::new(format!("Weapon {}", slot + 1), cells));
+    }
+
+    for slot in &[ArmorSlot::Head, ArmorSlot::Torso, ArmorSlot::Legs] {
+        let cells = equipment
+            .iter()
+            .map(|e| {
+                let id = match slot {
+                    ArmorSlot::Head => e.armor.head,
+                    ArmorSlot::Torso => e.armor.torso,
+                    ArmorSlot::Legs => e.armor.legs,
+                };
+
+                match catalog.armor_by_id(*slot, id) {
+                    Some(piece) => Cell::new(piece.name.clone()),
+                    None => Cell::new(unknown(id)),
+                }
+            })
+            .collect();
+
+        rows.push(Row::new(slot.to_string(), cells));
+    }
+
+    let perks = equipment
+        .iter()
+        .map(|e| {
+            let mut names = e
+                .perks
+                .iter()
+                .map(|&id| {
+                    catalog
+                        .perk_by_id(id)
+                        .map(|p| p.name.clone())
+                        .unwrap_or_else(|| unknown(id))
+                })
+                .collect::<Vec<_>>();
+            names.sort();
+
+            if names.is_empty() {
+                Cell::new("-")
+            } else {
+                Cell::new(names.join(", "))
+            }
+        })
+        .collect();
+
+    rows.push(Row::new("Perks", perks));
+
+    rows
+}
+
+// Perks equipped by every loadout being compared
+fn shared_perks(equipment: &[Equipment]) -> Vec<String> {
+    let catalog = catalog::current();
+
+    equipment[0]
+        .perks
+        .iter()
+        .filter(|perk| equipment[1..].iter().all(|e| e.perks.contains(perk)))
+        .map(|&id| {
+            catalog
+                .perk_by_id(id)
+                .map(|p| p.name.clone())
+                .unwrap_or_else(|| unknown(id))
+        })
+        .collect()
+}
+
+// Shown for items the catalog doesn't know, e.g from a newer patch
+fn unknown<T: std::fmt::Display>(id: T) -> String {
+    format!("Unknown ({})", id)
+}
+
+fn stat_delta(base: &Weapon, other: &Weapon) -> String {
+    let delta = |a: u32, b: u32| i64::from(b) - i64::from(a);
+
+    format!(
+        "{:+} slash, {:+} stab, {:+}ms speed, {:+}cm length",
+        delta(base.slash_damage, other.slash_damage),
+        delta(base.stab_damage, other.stab_damage),
+        delta(base.speed_ms, other.speed_ms),
+        delta(base.length_cm, other.length_cm),
+    )
+}
@@ -1,18 +1,425 @@
 use crate::{
-    app::{self, ActiveLink, State, TmplBase},
-    models::User,
+    app::{self, ActiveLink, PgPool, State, TmplBase},
+    catalog::{self, Weapon},
+    markdown,
+    models::{Guide, LoadoutMultiple, User},
+    routes::gen::guides::GUIDES,
 };
-use actix_web::HttpResponse;
+use actix_web::{web, HttpRequest, HttpResponse};
 use askama::Template;
+use futures::{future, stream::Stream, Future, IntoFuture};
+use std::collections::HashMap;
+
+const MAX_TITLE_LEN: usize = 120;
+const MAX_BODY_LEN: usize = 100_000;
+
+// Generated by build.rs for each guide in `markdown/guides/`, from the
+// guide's front-matter
+pub struct GuideInfo {
+    pub slug: &'static str,
+    pub title: &'static str,
+    pub summary: Option<&'static str>,
+    pub author: Option<u64>, // Steam ID
+    pub tags: &'static [&'static str],
+    pub published: Option<&'static str>,
+    pub updated: Option<&'static str>,
+    pub draft: bool,
+    pub weapons: &'static [GuideWeapon],
+    // The guide without any formatting, for search
+    pub text: &'static str,
+}
+
+pub struct GuideWeapon {
+    pub name: &'static str,
+    pub slug: &'static str,
+}
+
+#[derive(Template)]
+#[template(path = "loadouts/card.html")]
+struct LoadoutCard<'a> {
+    loadout: &'a LoadoutMultiple,
+}
+
+#[derive(Template)]
+#[template(path = "catalog/weapon_link.html")]
+struct WeaponLink<'a> {
+    name: &'a str,
+    weapon: Option<&'a Weapon>,
+}
+
+// Live content for the `{{loadout 42}}` and `{{weapon Zweihander}}` shortcodes
+// in guides, looked up when the page is rendered
+#[derive(Default)]
+pub struct Embeds {
+    loadouts: HashMap<i32, LoadoutMultiple>,
+}
+
+impl Embeds {
+    pub fn fetch(
+        loadout_ids: &'static [i32],
+        pool: &PgPool,
+    ) -> Box<dyn Future<Item = Self, Error = app::Error>> {
+        if loadout_ids.is_empty() {
+            return Box::new(future::ok(Self::default()));
+        }
+
+        Box::new(
+            LoadoutMultiple::query_by_ids(loadout_ids, None, pool).map(|loadouts| Self {
+                loadouts: loadouts.into_iter().map(|l| (l.id, l)).collect(),
+            }),
+        )
+    }
+
+    pub fn loadout_card(&self, id: i32) -> String {
+        match self.loadouts.get(&id) {
+            Some(loadout) => render_fragment(LoadoutCard { loadout }),
+            None => format!("<p class=\"uk-text-meta\">Loadout {} not found</p>", id),
+        }
+    }
+
+    pub fn weapon_link(&self, name: &str) -> String {
+        render_fragment(WeaponLink {
+            name,
+            weapon: catalog::current().weapon(name),
+        })
+    }
+}
+
+// Embeds are rendered in the middle of another template, so log errors
+// rather than failing the whole page
+fn render_fragment<T: Template>(tmpl: T) -> String {
+    tmpl.render().unwrap_or_else(|e| {
+        error!("failed to render guide embed: {}", e);
+        String::new()
+    })
+}
 
 #[derive(Template)]
 #[template(path = "guides/list.html")]
 struct GuidesList {
     base: TmplBase,
+    guides: &'static [GuideInfo],
 }
 
 pub fn list(user: Option<User>) -> Result<HttpResponse, app::Error> {
     State::render(GuidesList {
-        base: TmplBase::new(user, ActiveLink::Guides),
+        base: TmplBase::new(user, ActiveLink::Guides)
+            .with_title("Guides")
+            .with_canonical("/guides"),
+        guides: GUIDES,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "guides/community/list.html")]
+struct CommunityList {
+    base: TmplBase,
+    guides: Vec<Guide>,
+}
+
+pub fn community_list(
+    req: HttpRequest,
+    user: Option<User>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    let user_id = user.as_ref().map(|u| u.id);
+
+    Guide::query_multiple(user_id, state.read_db(&req)).and_then(move |guides| {
+        State::render(CommunityList {
+            base: TmplBase::new(user, ActiveLink::Guides)
+                .with_title("Community Guides")
+                .with_canonical("/guides/community"),
+            guides,
+        })
+    })
+}
+
+#[derive(Template)]
+#[template(path = "guides/community/single.html")]
+struct CommunitySingle {
+    base: TmplBase,
+    guide: Guide,
+    body: String,
+    is_author: bool,
+}
+
+pub fn community_single(
+    req: HttpRequest,
+    guide_id: web::Path<i32>,
+    user: Option<User>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    Guide::query(*guide_id, state.read_db(&req))
+        .and_then(|guide| guide.ok_or(app::Error::NotFound))
+        .and_then(move |guide| {
+            let is_author = user.as_ref().map_or(false, |u| u.id == guide.user_id);
+
+            // Drafts are only visible to their author
+            if !guide.published && !is_author {
+                return Err(app::Error::NotFound);
+            }
+
+            State::render(CommunitySingle {
+                base: TmplBase::new(user, ActiveLink::Guides)
+                    .with_title(guide.title.clone())
+                    .with_canonical(&format!("/guides/community/{}", guide.id)),
+                body: guide.render_body(),
+                guide,
+                is_author,
+            })
+        })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuideForm {
+    title: String,
+    body: String,
+    // Which button was pressed: `preview`, `draft`, `publish` or `suggest`
+    action: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FormAction {
+    Draft,
+    Publish,
+    // An edit to someone else's guide, saved as a pending revision
+    Suggest,
+}
+
+#[derive(Template)]
+#[template(path = "guides/community/edit.html")]
+struct CommunityEdit {
+    base: TmplBase,
+    guide_id: Option<i32>,
+    title: String,
+    body: String,
+    preview: Option<String>,
+    suggestion: bool,
+}
+
+fn render_editor(
+    user: User,
+    guide_id: Option<i32>,
+    title: String,
+    body: String,
+    preview: Option<String>,
+    suggestion: bool,
+) -> Result<HttpResponse, app::Error> {
+    State::render(CommunityEdit {
+        base: TmplBase::new(Some(user), ActiveLink::Guides),
+        guide_id,
+        title,
+        body,
+        preview,
+        suggestion,
     })
 }
+
+impl GuideForm {
+    fn validate(&self) -> Result<FormAction, app::Error> {
+        if self.title.trim().is_empty() || self.title.chars().count() > MAX_TITLE_LEN {
+            return Err(app::Error::BadRequest(format!(
+                "title must be between 1 and {} characters",
+                MAX_TITLE_LEN
+            )));
+        }
+
+        if self.body.trim().is_empty() || self.body.chars().count() > MAX_BODY_LEN {
+            return Err(app::Error::BadRequest(format!(
+                "body must be between 1 and {} characters",
+                MAX_BODY_LEN
+            )));
+        }
+
+        match self.action.as_str() {
+            "draft" => Ok(FormAction::Draft),
+            "publish" => Ok(FormAction::Publish),
+            "suggest" => Ok(FormAction::Suggest),
+            _ => Err(app::Error::BadRequest(format!(
+                "unknown action '{}'",
+                self.action
+            ))),
+        }
+    }
+
+    fn preview(&self) -> Option<String> {
+        if self.action == "preview" {
+            Some(ammonia::clean(&markdown::to_html(&self.body)))
+        } else {
+            None
+        }
+    }
+}
+
+pub fn community_create_get(user: Option<User>) -> Result<HttpResponse, app::Error> {
+    let user = user.ok_or(app::Error::RedirectToLogin)?;
+    render_editor(user, None, String::new(), String::new(), None, false)
+}
+
+pub fn community_create_post(
+    user: User,
+    form: web::Form<GuideForm>,
+    state: web::Data<State>,
+) -> Box<dyn Future<Item = HttpResponse, Error = app::Error>> {
+    // TODO: Check CSRF token
+    let form = form.into_inner();
+
+    if let Some(preview) = form.preview() {
+        return Box::new(
+            render_editor(user, None, form.title, form.body, Some(preview), false).into_future(),
+        );
+    }
+
+    let published = match form.validate() {
+        Ok(FormAction::Draft) => false,
+        Ok(FormAction::Publish) => true,
+        Ok(FormAction::Suggest) => {
+            return Box::new(future::err(app::Error::BadRequest(
+                "can't suggest an edit to a new guide".to_owned(),
+            )))
+        }
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    let state2 = state.clone();
+
+    Box::new(
+        state
+            .get_db()
+            .connection()
+            .from_err()
+            .and_then(move |mut conn| {
+                let conn = &mut *conn;
+                conn.query(
+                    |q| &q.create_guide,
+                    &[&user.id, &form.title, &form.body, &published],
+                )
+                .into_future()
+                .map(|(r, _)| r)
+                .map_err(|(e, _)| app::Error::from(e))
+            })
+            .and_then(|row| match row {
+                Some(row) => Ok(row.get::<_, i32>(0)),
+                None => Err(app::Error::DbNothingReturned),
+            })
+            .and_then(move |guide_id| {
+                Ok(state2.after_write(
+                    HttpResponse::SeeOther()
+                        .header("Location", format!("/guides/community/{}", guide_id))
+                        .finish(),
+                ))
+            }),
+    )
+}
+
+pub fn community_edit_get(
+    guide_id: web::Path<i32>,
+    user: Option<User>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    user.ok_or(app::Error::RedirectToLogin)
+        .into_future()
+        .and_then(move |user| {
+            Guide::query(*guide_id, state.get_db())
+                .and_then(|guide| guide.ok_or(app::Error::NotFound))
+                .and_then(move |guide| {
+                    let suggestion = guide.user_id != user.id;
+
+                    // Anyone can suggest edits to a published guide, but
+                    // drafts are only visible to their author
+                    if suggestion && !guide.published {
+                        return Err(app::Error::NotFound);
+                    }
+
+                    render_editor(
+                        user,
+                        Some(guide.id),
+                        guide.title,
+                        guide.body,
+                        None,
+                        suggestion,
+                    )
+                })
+        })
+}
+
+pub fn community_edit_post(
+    guide_id: web::Path<i32>,
+    user: User,
+    form: web::Form<GuideForm>,
+    state: web::Data<State>,
+) -> Box<dyn Future<Item = HttpResponse, Error = app::Error>> {
+    // TODO: Check CSRF token
+    let guide_id = *guide_id;
+    let form = form.into_inner();
+
+    if let Some(preview) = form.preview() {
+        return Box::new(
+            Guide::query(guide_id, state.get_db())
+                .and_then(|guide| guide.ok_or(app::Error::NotFound))
+                .and_then(move |guide| {
+                    let suggestion = guide.user_id != user.id;
+                    render_editor(
+                        user,
+                        Some(guide_id),
+                        form.title,
+                        form.body,
+                        Some(preview),
+                        suggestion,
+                    )
+                }),
+        );
+    }
+
+    let action = match form.validate() {
+        Ok(action) => action,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    let state2 = state.clone();
+
+    Box::new(
+        state
+            .get_db()
+            .connection()
+            .from_err()
+            .and_then(move |mut conn| {
+                let conn = &mut *conn;
+                let query = match action {
+                    FormAction::Suggest => conn.execute(
+                        |q| &q.suggest_guide_revision,
+                        &[&guide_id, &user.id, &form.title, &form.body],
+                    ),
+                    FormAction::Draft | FormAction::Publish => {
+                        let published = action == FormAction::Publish;
+                        conn.execute(
+                            |q| &q.update_guide,
+                            &[&form.title, &form.body, &published, &guide_id, &user.id],
+                        )
+                    }
+                };
+
+                query.from_err()
+            })
+            .and_then(move |updated| {
+                // Nothing is updated if the guide doesn't exist, or if it's
+                // someone else's guide and this wasn't a suggestion
+                if updated == 0 {
+                    return Err(app::Error::NotFound);
+                }
+
+                let location = match action {
+                    FormAction::Suggest => format!("/guides/community/{}/revisions", guide_id),
+                    FormAction::Draft | FormAction::Publish => {
+                        format!("/guides/community/{}", guide_id)
+                    }
+                };
+
+                Ok(state2.after_write(
+                    HttpResponse::SeeOther()
+                        .header("Location", location)
+                        .finish(),
+                ))
+            }),
+    )
+}
@@ -0,0 +1,130 @@
+// Endpoints for docker and any other orchestration to check on the server
+
+use crate::{
+    app::{self, State},
+    files,
+    shutdown,
+};
+use actix_web::{web, HttpResponse};
+use futures::{
+    future::{self, Either},
+    stream::Stream,
+    Future,
+};
+use std::{collections::HashSet, fs};
+
+const MIGRATIONS_DIR: &str = "db/migrations";
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    ok: bool,
+    errors: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    database: Check,
+    migrations: Check,
+    files: Check,
+}
+
+impl Check {
+    fn new(errors: Vec<String>) -> Self {
+        Self {
+            ok: errors.is_empty(),
+            errors,
+        }
+    }
+}
+
+// The server is up and handling requests
+pub fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(Health { status: "ok" })
+}
+
+// The server is able to do its job: the database is reachable and fully
+// migrated, and the files it serves from disk are there. Fails as soon as the
+// server starts shutting down, so that it stops being sent requests.
+pub fn readyz(state: web::Data<State>) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    if shutdown::in_progress() {
+        let health = Health {
+            status: "shutting down",
+        };
+        return Either::A(future::ok(HttpResponse::ServiceUnavailable().json(health)));
+    }
+
+    let readiness = state
+        .get_db()
+        .connection()
+        .from_err::<app::Error>()
+        .and_then(|mut conn| {
+            let ping = conn.ping();
+            let applied = conn.query(|q| &q.applied_migrations, &[]).collect();
+
+            ping.and_then(|_| applied).from_err()
+        })
+        .then(|applied| {
+            let (database, migrations) = match applied {
+                Ok(rows) => {
+                    let applied = rows
+                        .iter()
+                        .map(|row| row.get(0))
+                        .collect::<HashSet<String>>();
+                    (
+                        Check::new(Vec::new()),
+                        Check::new(pending_migrations(&applied)),
+                    )
+                }
+                Err(e) => (
+                    Check::new(vec![e.to_string()]),
+                    Check::new(vec!["can't check migrations without a database".to_owned()]),
+                ),
+            };
+
+            let files = Check::new(files::check().iter().map(ToString::to_string).collect());
+
+            let readiness = Readiness {
+                ready: database.ok && migrations.ok && files.ok,
+                database,
+                migrations,
+                files,
+            };
+
+            if readiness.ready {
+                Ok(HttpResponse::Ok().json(readiness))
+            } else {
+                Ok(HttpResponse::ServiceUnavailable().json(readiness))
+            }
+        });
+
+    Either::B(readiness)
+}
+
+// Migrations in `db/migrations` that haven't been applied to the database.
+// dbmate names them `<version>_<name>.sql`.
+fn pending_migrations(applied: &HashSet<String>) -> Vec<String> {
+    let entries = match fs::read_dir(MIGRATIONS_DIR) {
+        Ok(entries) => entries,
+        Err(e) => return vec![format!("failed to read {}: {}", MIGRATIONS_DIR, e)],
+    };
+
+    let mut pending = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".sql"))
+        .filter_map(|name| name.split('_').next().map(str::to_owned))
+        .filter(|version| !applied.contains(version))
+        .collect::<Vec<_>>();
+
+    pending.sort();
+    pending
+        .into_iter()
+        .map(|version| format!("migration {} has not been applied", version))
+        .collect()
+}
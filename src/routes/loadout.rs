@@ -1,10 +1,19 @@
 use crate::{
-    app::{self, ActiveLink, State, TmplBase},
+    app::{self, ActiveLink, PgConn, State, TmplBase},
+    catalog::{self, Weapon},
+    cost::{self, CostReport},
+    equipment::Equipment,
     models::{Image, LoadoutMultiple, LoadoutSingle, User},
 };
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use askama::Template;
-use futures::{stream::Stream, Future};
+use futures::{
+    future::{self, Either},
+    stream::Stream,
+    Future,
+    IntoFuture,
+};
+use std::ops::DerefMut;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct CreateLoadout {
@@ -14,25 +23,105 @@ pub struct CreateLoadout {
     cloudinary_format: String,
 }
 
+impl CreateLoadout {
+    fn image_url(&self) -> String {
+        format!(
+            "https://res.cloudinary.com/zeta64/image/upload/{}.{}",
+            self.cloudinary_id, self.cloudinary_format
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ListFilter {
+    // Only show loadouts which are within the point budget and have no problems
+    #[serde(default)]
+    valid: bool,
+    // Hide loadouts whose cost changed or became invalid in a later patch
+    #[serde(default)]
+    hide_outdated: bool,
+}
+
+impl ListFilter {
+    // The url of the list with this filter applied
+    fn url(&self) -> String {
+        let mut params = Vec::new();
+        if self.valid {
+            params.push("valid=true");
+        }
+        if self.hide_outdated {
+            params.push("hide_outdated=true");
+        }
+
+        if params.is_empty() {
+            "/loadouts".to_owned()
+        } else {
+            format!("/loadouts?{}", params.join("&"))
+        }
+    }
+
+    // For the filter links, which each change one option and keep the other
+    fn toggle_valid(&self) -> Self {
+        Self {
+            valid: !self.valid,
+            ..self.clone()
+        }
+    }
+
+    fn toggle_hide_outdated(&self) -> Self {
+        Self {
+            hide_outdated: !self.hide_outdated,
+            ..self.clone()
+        }
+    }
+}
+
 #[derive(Template)]
 #[template(path = "loadouts/list.html")]
 struct LoadoutList {
     base: TmplBase,
     loadouts: Vec<LoadoutMultiple>,
+    filter: ListFilter,
 }
 
 pub fn list(
+    req: HttpRequest,
     user: Option<User>,
+    filter: web::Query<ListFilter>,
     state: web::Data<State>,
 ) -> impl Future<Item = HttpResponse, Error = app::Error> {
     let user2 = user.clone();
+    let filter = filter.into_inner();
 
-    LoadoutMultiple::query(user2, state.get_db()).and_then(move |loadouts| {
-        State::render(LoadoutList {
-            base: TmplBase::new(user, ActiveLink::Loadouts),
-            loadouts,
-        })
-    })
+    let cache_key = if user.is_none() {
+        Some(filter.url())
+    } else {
+        None
+    };
+
+    if let Some(page) = state.cached_page(cache_key.as_ref().map(String::as_str)) {
+        return Either::A(future::ok(page));
+    }
+
+    Either::B(
+        LoadoutMultiple::query(user2, state.cache_db(&req)).and_then(move |mut loadouts| {
+            if filter.valid {
+                loadouts.retain(|l| cost::evaluate(&l.data).valid);
+            }
+            if filter.hide_outdated {
+                loadouts.retain(|l| !l.patch_status().is_outdated());
+            }
+
+            state.render_cached(
+                cache_key,
+                LoadoutList {
+                    base: TmplBase::new(user, ActiveLink::Loadouts),
+                    loadouts,
+                    filter,
+                },
+            )
+        }),
+    )
 }
 
 #[derive(Template)]
@@ -58,80 +147,376 @@ pub fn create_post(
 ) -> impl Future<Item = HttpResponse, Error = app::Error> {
     // TODO: Check CSRF token
     // TODO: Sanitize these inputs
-    let cloudinary_url = format!(
-        "https://res.cloudinary.com/zeta64/image/upload/{}.{}",
-        &form.cloudinary_id, &form.cloudinary_format
-    );
-
+    let form = form.into_inner();
     let user_id = user.id;
+    let game_version = &catalog::current().version;
+
+    let state2 = state.clone();
 
     state
         .get_db()
         .connection()
-        .from_err()
-        .and_then(move |mut conn_ptr| {
-            let conn = &mut *conn_ptr;
-            conn.client
-                .query(
-                    &conn.queries.create_loadout,
-                    &[&user_id, &form.name, &form.data],
+        .and_then(move |conn| {
+            // The loadout and its image are created together, or not at all
+            PgConn::transaction(conn, move |mut conn| {
+                conn.query(
+                    |q| &q.create_loadout,
+                    &[&user_id, &form.name, &form.data, game_version],
                 )
                 .into_future()
-                .map(|(r, _)| (conn_ptr, r))
                 .map_err(|(e, _)| app::Error::from(e))
+                .and_then(|(row, _)| match row {
+                    Some(row) => Ok(row.get::<_, i32>(0)),
+                    None => Err(app::Error::DbNothingReturned),
+                })
+                .and_then(move |loadout_id| {
+                    // TODO: Handle position when multiple images uploaded
+                    conn.execute(
+                        |q| &q.create_image,
+                        &[&form.image_url(), &loadout_id, &0i32],
+                    )
+                    .from_err()
+                    .map(move |_| (conn, loadout_id))
+                })
+            })
         })
-        .and_then(|(conn, row)| match row {
-            Some(row) => Ok((conn, row.get::<_, i32>(0))),
-            None => Err(app::Error::DbNothingReturned),
+        .map(move |(_, loadout_id)| {
+            state2.invalidate_loadout(loadout_id);
+
+            state2.after_write(
+                HttpResponse::SeeOther()
+                    .header("Location", format!("/loadouts/{}", loadout_id))
+                    .finish(),
+            )
         })
-        .and_then(move |(mut conn, loadout_id)| {
-            // TODO: Handle position when multiple images uploaded
-            let conn = &mut *conn;
-            conn.client
-                .query(
-                    &conn.queries.create_image,
-                    &[&cloudinary_url, &loadout_id, &0i32],
-                )
-                .into_future()
-                .map_err(|(e, _)| e)
-                .map(move |_| loadout_id)
-                .from_err()
+}
+
+#[derive(Template)]
+#[template(path = "loadouts/edit.html")]
+struct LoadoutEdit {
+    base: TmplBase,
+    loadout: LoadoutSingle,
+}
+
+pub fn edit_get(
+    ld_id: web::Path<i32>,
+    user: Option<User>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    let ld_id = *ld_id;
+
+    user.ok_or(app::Error::RedirectToLogin)
+        .into_future()
+        .and_then(move |user| {
+            state.get_db().connection().and_then(move |mut conn| {
+                LoadoutSingle::query(ld_id, Some(user.clone()), &mut conn)
+                    .map(move |loadout| (user, loadout))
+            })
+        })
+        .and_then(|(user, loadout)| {
+            let loadout = loadout.ok_or(app::Error::NotFound)?;
+            if loadout.user_id != user.id {
+                return Err(app::Error::Forbidden);
+            }
+
+            Ok((user, loadout))
+        })
+        .and_then(|(user, loadout)| {
+            State::render(LoadoutEdit {
+                base: TmplBase::new(Some(user), ActiveLink::Loadouts)
+                    .with_title(format!("Edit {}", loadout.name)),
+                loadout,
+            })
+        })
+}
+
+pub fn edit_post(
+    ld_id: web::Path<i32>,
+    user: User,
+    form: web::Form<CreateLoadout>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    // TODO: Check CSRF token
+    let ld_id = *ld_id;
+    let form = form.into_inner();
+    let game_version = &catalog::current().version;
+    let state2 = state.clone();
+
+    state
+        .get_db()
+        .connection()
+        .and_then(move |conn| {
+            PgConn::transaction(conn, move |conn| {
+                lock_owned(conn, ld_id, &user)
+                    .and_then(move |mut conn| {
+                        conn.execute(
+                            |q| &q.update_loadout,
+                            &[&form.name, &form.data, game_version, &ld_id],
+                        )
+                        .from_err()
+                        .map(move |_| (conn, form))
+                    })
+                    .and_then(move |(mut conn, form)| {
+                        // Leave the images alone unless a new one was uploaded
+                        if form.cloudinary_id.is_empty() {
+                            return Either::A(future::ok((conn, ())));
+                        }
+
+                        let delete = conn.execute(|q| &q.delete_loadout_images, &[&ld_id]);
+                        let create =
+                            conn.execute(|q| &q.create_image, &[&form.image_url(), &ld_id, &0i32]);
+
+                        Either::B(delete.and_then(|_| create).from_err().map(|_| (conn, ())))
+                    })
+            })
         })
-        .and_then(|loadout_id| {
-            Ok(HttpResponse::SeeOther()
-                .header("Location", format!("/loadouts/{}", loadout_id))
-                .finish())
+        .map(move |_| {
+            state2.invalidate_loadout(ld_id);
+
+            state2.after_write(
+                HttpResponse::SeeOther()
+                    .header("Location", format!("/loadouts/{}", ld_id))
+                    .finish(),
+            )
         })
 }
 
+pub fn delete(
+    ld_id: web::Path<i32>,
+    user: User,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    // TODO: Check CSRF token
+    let ld_id = *ld_id;
+    let state2 = state.clone();
+
+    state
+        .get_db()
+        .connection()
+        .and_then(move |conn| {
+            PgConn::transaction(conn, move |conn| {
+                lock_owned(conn, ld_id, &user).and_then(move |mut conn| {
+                    conn.execute(|q| &q.delete_loadout, &[&ld_id])
+                        .from_err()
+                        .map(|_| (conn, ()))
+                })
+            })
+        })
+        .map(move |_| {
+            state2.invalidate_loadout(ld_id);
+
+            state2.after_write(
+                HttpResponse::SeeOther()
+                    .header("Location", "/loadouts")
+                    .finish(),
+            )
+        })
+}
+
+// Like the loadout, or take the like back if it was already liked
+pub fn toggle_like(
+    ld_id: web::Path<i32>,
+    user: User,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    // TODO: Check CSRF token
+    let ld_id = *ld_id;
+    let state2 = state.clone();
+
+    state
+        .get_db()
+        .connection()
+        .and_then(move |conn| {
+            // Locking the loadout stops two toggles at once from both liking it
+            PgConn::transaction(conn, move |conn| {
+                lock(conn, ld_id)
+                    .and_then(move |(mut conn, _)| {
+                        conn.execute(|q| &q.remove_like, &[&user.id, &ld_id])
+                            .from_err()
+                            .map(move |removed| (conn, user, removed))
+                    })
+                    .and_then(move |(mut conn, user, removed)| {
+                        if removed > 0 {
+                            return Either::A(future::ok((conn, ())));
+                        }
+
+                        Either::B(
+                            conn.execute(|q| &q.add_like, &[&user.id, &ld_id])
+                                .from_err()
+                                .map(|_| (conn, ())),
+                        )
+                    })
+            })
+        })
+        .map(move |_| {
+            state2.invalidate_loadout(ld_id);
+
+            state2.after_write(
+                HttpResponse::SeeOther()
+                    .header("Location", format!("/loadouts/{}", ld_id))
+                    .finish(),
+            )
+        })
+}
+
+// Lock a loadout for the rest of the transaction, returning who owns it
+fn lock<C>(mut conn: C, loadout_id: i32) -> impl Future<Item = (C, i32), Error = app::Error>
+where
+    C: DerefMut<Target = PgConn>,
+{
+    conn.query(|q| &q.lock_loadout, &[&loadout_id])
+        .into_future()
+        .map_err(|(e, _)| app::Error::from(e))
+        .and_then(move |(row, _)| match row {
+            Some(row) => Ok((conn, row.get(0))),
+            None => Err(app::Error::NotFound),
+        })
+}
+
+// Like `lock`, but only if the loadout belongs to `user`
+fn lock_owned<C>(conn: C, loadout_id: i32, user: &User) -> impl Future<Item = C, Error = app::Error>
+where
+    C: DerefMut<Target = PgConn>,
+{
+    let user_id = user.id;
+
+    lock(conn, loadout_id).and_then(move |(conn, owner)| {
+        if owner == user_id {
+            Ok(conn)
+        } else {
+            Err(app::Error::Forbidden)
+        }
+    })
+}
+
+// The loadouts created against one patch
+struct PatchGroup {
+    version: String,
+    loadouts: Vec<LoadoutMultiple>,
+    outdated: usize,
+}
+
+#[derive(Template)]
+#[template(path = "loadouts/patch_report.html")]
+struct PatchReport {
+    base: TmplBase,
+    current_version: &'static str,
+    // Newest patch first
+    patches: Vec<PatchGroup>,
+}
+
+// Shows the logged in user how their loadouts from each patch fare under the
+// current one
+pub fn patch_report(
+    req: HttpRequest,
+    user: Option<User>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    user.ok_or(app::Error::RedirectToLogin)
+        .into_future()
+        .and_then(move |user| {
+            LoadoutMultiple::query_by_user(user.id, Some(user.clone()), state.read_db(&req))
+                .and_then(move |loadouts| {
+                    State::render(PatchReport {
+                        base: TmplBase::new(Some(user), ActiveLink::Loadouts),
+                        current_version: &catalog::current().version,
+                        patches: group_by_patch(loadouts),
+                    })
+                })
+        })
+}
+
+fn group_by_patch(mut loadouts: Vec<LoadoutMultiple>) -> Vec<PatchGroup> {
+    loadouts.sort_by(|a, b| {
+        catalog::version_key(&b.game_version)
+            .cmp(&catalog::version_key(&a.game_version))
+            .then_with(|| a.name.cmp(&b.name))
+    });
+
+    let mut patches: Vec<PatchGroup> = Vec::new();
+
+    for loadout in loadouts {
+        let outdated = loadout.patch_status().is_outdated() as usize;
+
+        match patches.last_mut() {
+            Some(group) if group.version == loadout.game_version => {
+                group.outdated += outdated;
+                group.loadouts.push(loadout);
+            }
+            _ => patches.push(PatchGroup {
+                version: loadout.game_version.clone(),
+                loadouts: vec![loadout],
+                outdated,
+            }),
+        }
+    }
+
+    patches
+}
+
 #[derive(Template)]
 #[template(path = "loadouts/single.html")]
 struct LoadoutSingleTmpl {
     base: TmplBase,
     loadout: LoadoutSingle,
     images: Vec<Image>,
+    cost: CostReport,
+    weapons: Vec<Weapon>,
+    is_owner: bool,
 }
 
 pub fn single(
+    req: HttpRequest,
     ld_id: web::Path<u32>,
     user: Option<User>,
     state: web::Data<State>,
 ) -> impl Future<Item = HttpResponse, Error = app::Error> {
     state
-        .get_db()
+        .cache_db(&req)
         .connection()
         .from_err()
         .and_then(move |mut conn| {
-            let loadout_future = LoadoutSingle::query(*ld_id as i32, user.clone(), &mut conn)
-                .and_then(|ldt| ldt.ok_or(app::Error::NotFound));
+            let loadout_future = LoadoutSingle::query_cached(
+                *ld_id as i32,
+                user.clone(),
+                &mut conn,
+                state.loadout_cache(),
+            )
+            .and_then(|ldt| ldt.ok_or(app::Error::NotFound));
 
             let images_future = Image::query(*ld_id as i32, &mut conn);
 
             // Run queries in parallel
             images_future.join(loadout_future).and_then(
                 move |(images, loadout): (Vec<Image>, LoadoutSingle)| {
+                    let is_owner = user.as_ref().map_or(false, |u| u.id == loadout.user_id);
+
+                    let mut base = TmplBase::new(user, ActiveLink::Loadouts)
+                        .with_title(loadout.name.clone())
+                        .with_description(format!(
+                            "A Mordhau loadout on MordHub with {} likes.",
+                            loadout.like_count
+                        ))
+                        .with_canonical(&format!("/loadouts/{}", loadout.id));
+
+                    if let Some(image) = images.first() {
+                        base = base.with_image(image.url.clone());
+                    }
+
+                    let weapons = Equipment::parse(&loadout.data)
+                        .map(|e| {
+                            e.weapons
+                                .iter()
+                                .filter_map(|&id| catalog::current().weapon_by_id(id).cloned())
+                                .collect()
+                        })
+                        .unwrap_or_default();
+
                     State::render(LoadoutSingleTmpl {
-                        base: TmplBase::new(user, ActiveLink::Loadouts),
+                        base,
+                        cost: cost::evaluate(&loadout.data),
+                        weapons,
+                        is_owner,
                         loadout,
                         images,
                     })
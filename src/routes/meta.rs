@@ -1,9 +1,12 @@
 use crate::{
     app::{self, ActiveLink, State, TmplBase},
+    files,
+    metrics,
     models::User,
 };
-use actix_web::HttpResponse;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
 use askama::Template;
+use prometheus::{Encoder, TextEncoder};
 
 #[derive(Template)]
 #[template(path = "index.html")]
@@ -28,3 +31,37 @@ pub fn about(user: Option<User>) -> Result<HttpResponse, app::Error> {
         base: TmplBase::new(user, ActiveLink::About),
     })
 }
+
+pub fn robots() -> Result<HttpResponse, app::Error> {
+    let robots = files::read(files::ROBOTS_TXT)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain")
+        .body(robots.to_string()))
+}
+
+// The default service's handlers, for requests no route matched
+
+pub fn not_found(req: HttpRequest) -> HttpResponse {
+    metrics::mark_unmatched(&req);
+    app::Error::NotFound.render_response()
+}
+
+pub fn method_not_allowed(req: HttpRequest) -> HttpResponse {
+    metrics::mark_unmatched(&req);
+    HttpResponse::MethodNotAllowed().finish()
+}
+
+pub fn metrics(state: web::Data<State>) -> Result<HttpResponse, app::Error> {
+    state.record_metrics();
+
+    let encoder = TextEncoder::new();
+    let mut buf = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buf)
+        .map_err(|_| app::Error::Internal)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buf))
+}
@@ -1,7 +1,13 @@
 pub mod api;
+pub mod assets;
 pub mod auth;
+pub mod catalog;
+pub mod compare;
 pub mod gen;
 pub mod guides;
+pub mod health;
 pub mod loadout;
 pub mod meta;
+pub mod revisions;
+pub mod search;
 pub mod user;
@@ -0,0 +1,242 @@
+use crate::{
+    app::{self, ActiveLink, State, TmplBase},
+    models::{Guide, GuideRevision, RevisionStatus, User},
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use difference::{Changeset, Difference};
+use futures::Future;
+use tokio_postgres::types::ToSql;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    Same,
+    Added,
+    Removed,
+}
+
+struct DiffLine {
+    kind: LineKind,
+    text: String,
+}
+
+impl DiffLine {
+    fn prefix(&self) -> &'static str {
+        match self.kind {
+            LineKind::Same => " ",
+            LineKind::Added => "+",
+            LineKind::Removed => "-",
+        }
+    }
+
+    fn css_class(&self) -> &'static str {
+        match self.kind {
+            LineKind::Same => "",
+            LineKind::Added => "uk-text-success",
+            LineKind::Removed => "uk-text-danger",
+        }
+    }
+}
+
+// The author of a guide and moderators decide which suggestions are accepted
+fn can_review(user: &User, guide: &Guide) -> bool {
+    user.id == guide.user_id || user.is_moderator
+}
+
+fn can_view(user: Option<&User>, guide: &Guide) -> bool {
+    guide.published || user.map_or(false, |u| can_review(u, guide))
+}
+
+// Line by line diff of two guide bodies
+fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let changeset = Changeset::new(old, new, "\n");
+
+    changeset
+        .diffs
+        .into_iter()
+        .flat_map(|diff| {
+            let (kind, text) = match diff {
+                Difference::Same(text) => (LineKind::Same, text),
+                Difference::Add(text) => (LineKind::Added, text),
+                Difference::Rem(text) => (LineKind::Removed, text),
+            };
+
+            text.split('\n')
+                .map(|line| DiffLine {
+                    kind,
+                    text: line.to_owned(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn query_guide_and_revisions(
+    req: &HttpRequest,
+    guide_id: i32,
+    user: Option<&User>,
+    state: &State,
+) -> impl Future<Item = (Guide, Vec<GuideRevision>), Error = app::Error> {
+    let user = user.cloned();
+
+    Guide::query(guide_id, state.read_db(req))
+        .join(GuideRevision::query_for_guide(guide_id, state.read_db(req)))
+        .and_then(move |(guide, revisions)| {
+            guide
+                .filter(|guide| can_view(user.as_ref(), guide))
+                .map(|guide| (guide, revisions))
+                .ok_or(app::Error::NotFound)
+        })
+}
+
+#[derive(Template)]
+#[template(path = "guides/community/revisions.html")]
+struct RevisionList {
+    base: TmplBase,
+    guide: Guide,
+    // Newest first
+    revisions: Vec<GuideRevision>,
+    can_review: bool,
+}
+
+pub fn list(
+    req: HttpRequest,
+    guide_id: web::Path<i32>,
+    user: Option<User>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    query_guide_and_revisions(&req, *guide_id, user.as_ref(), &state).and_then(
+        move |(guide, mut revisions)| {
+            revisions.reverse();
+
+            State::render(RevisionList {
+                can_review: user.as_ref().map_or(false, |u| can_review(u, &guide)),
+                base: TmplBase::new(user, ActiveLink::Guides)
+                    .with_title(format!("Revisions of {}", guide.title)),
+                guide,
+                revisions,
+            })
+        },
+    )
+}
+
+#[derive(Template)]
+#[template(path = "guides/community/diff.html")]
+struct RevisionDiff {
+    base: TmplBase,
+    guide: Guide,
+    revision: GuideRevision,
+    // The title and body the revision is compared against
+    old_title: String,
+    lines: Vec<DiffLine>,
+    can_review: bool,
+}
+
+pub fn diff(
+    req: HttpRequest,
+    path: web::Path<(i32, i32)>,
+    user: Option<User>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    let (guide_id, revision_id) = *path;
+
+    query_guide_and_revisions(&req, guide_id, user.as_ref(), &state).and_then(
+        move |(guide, revisions)| {
+            let index = revisions
+                .iter()
+                .position(|r| r.id == revision_id)
+                .ok_or(app::Error::NotFound)?;
+            let revision = revisions[index].clone();
+
+            // Suggestions are compared against the guide as it is now, and
+            // everything else against the accepted revision before it
+            let (old_title, old_body) = if revision.is_pending() {
+                (guide.title.clone(), guide.body.clone())
+            } else {
+                revisions[..index]
+                    .iter()
+                    .rev()
+                    .find(|r| r.status == RevisionStatus::Accepted)
+                    .map(|r| (r.title.clone(), r.body.clone()))
+                    .unwrap_or_default()
+            };
+
+            State::render(RevisionDiff {
+                can_review: user.as_ref().map_or(false, |u| can_review(u, &guide)),
+                base: TmplBase::new(user, ActiveLink::Guides)
+                    .with_title(format!("Revision of {}", guide.title)),
+                lines: diff_lines(&old_body, &revision.body),
+                old_title,
+                guide,
+                revision,
+            })
+        },
+    )
+}
+
+pub fn accept(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    review(*path, user, true, state)
+}
+
+pub fn reject(
+    path: web::Path<(i32, i32)>,
+    user: User,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    review(*path, user, false, state)
+}
+
+fn review(
+    (guide_id, revision_id): (i32, i32),
+    user: User,
+    accept: bool,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    // TODO: Check CSRF token
+    let state2 = state.clone();
+
+    Guide::query(guide_id, state.get_db())
+        .and_then(|guide| guide.ok_or(app::Error::NotFound))
+        .and_then(move |guide| {
+            if can_review(&user, &guide) {
+                Ok(user)
+            } else {
+                Err(app::Error::Forbidden)
+            }
+        })
+        .and_then(move |user| {
+            state
+                .get_db()
+                .connection()
+                .from_err()
+                .and_then(move |mut conn| {
+                    let params: &[&dyn ToSql] = &[&revision_id, &guide_id, &user.id];
+                    let query = if accept {
+                        conn.execute(|q| &q.accept_guide_revision, params)
+                    } else {
+                        conn.execute(|q| &q.reject_guide_revision, params)
+                    };
+
+                    query.from_err()
+                })
+        })
+        .and_then(move |updated| {
+            // Only pending revisions can be reviewed
+            if updated == 0 {
+                return Err(app::Error::NotFound);
+            }
+
+            Ok(state2.after_write(
+                HttpResponse::SeeOther()
+                    .header(
+                        "Location",
+                        format!("/guides/community/{}/revisions", guide_id),
+                    )
+                    .finish(),
+            ))
+        })
+}
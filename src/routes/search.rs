@@ -0,0 +1,39 @@
+use crate::{
+    app::{self, ActiveLink, State, TmplBase},
+    models::User,
+    search::{self, SearchResults},
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use askama::Template;
+use futures::Future;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    #[serde(default)]
+    pub q: String,
+}
+
+#[derive(Template)]
+#[template(path = "search.html")]
+struct Search {
+    base: TmplBase,
+    results: SearchResults,
+}
+
+pub fn search(
+    req: HttpRequest,
+    query: web::Query<SearchQuery>,
+    user: Option<User>,
+    state: web::Data<State>,
+) -> impl Future<Item = HttpResponse, Error = app::Error> {
+    search::search(&query.q, state.read_db(&req)).and_then(move |results| {
+        State::render(Search {
+            base: TmplBase::new(user, ActiveLink::None).with_title(if results.query.is_empty() {
+                "Search".to_owned()
+            } else {
+                format!("Search results for {}", results.query)
+            }),
+            results,
+        })
+    })
+}
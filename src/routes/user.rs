@@ -2,7 +2,7 @@ use crate::{
     app::{self, ActiveLink, State, TmplBase},
     models::{user::SteamId, User},
 };
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use askama::Template;
 use futures::Future;
 
@@ -14,15 +14,27 @@ struct UserProfile {
 }
 
 pub fn user_profile(
+    req: HttpRequest,
     user_id: web::Path<SteamId>,
     user: Option<User>,
     state: web::Data<State>,
 ) -> impl Future<Item = HttpResponse, Error = app::Error> {
-    User::get_by_steam_id(*user_id, state.get_db())
+    User::get_by_steam_id(*user_id, state.read_db(&req))
         .and_then(|target| target.ok_or(app::Error::NotFound))
         .and_then(move |target| {
             State::render(UserProfile {
-                base: TmplBase::new(user, ActiveLink::None),
+                base: TmplBase::new(user, ActiveLink::None)
+                    .with_title(
+                        target
+                            .persona_name
+                            .clone()
+                            .unwrap_or_else(|| format!("User {}", target.id)),
+                    )
+                    .with_description(format!(
+                        "Loadouts shared on MordHub by Steam user {}.",
+                        target.steam_id
+                    ))
+                    .with_canonical(&format!("/users/{}", target.steam_id)),
                 target,
             })
         })
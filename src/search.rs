@@ -0,0 +1,187 @@
+// Site search over loadouts, guides and users, using Postgres full-text search
+
+use crate::{
+    app::{self, PgPool},
+    models::user::SteamId,
+    routes::gen::guides::GUIDES,
+};
+use futures::{future, stream::Stream, Future};
+
+// Results shown for each kind of item
+pub const LIMIT: i64 = 10;
+
+// Postgres wraps matches in these, and we swap them for `<mark>` tags after
+// escaping the rest of the snippet
+const START_SEL: &str = "\u{27e6}";
+const STOP_SEL: &str = "\u{27e7}";
+
+lazy_static::lazy_static! {
+    static ref HEADLINE_OPTIONS: String = format!(
+        "StartSel={}, StopSel={}, MaxWords=30, MinWords=10, MaxFragments=2",
+        START_SEL, STOP_SEL
+    );
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadoutHit {
+    pub id: i32,
+    pub name: String,
+    pub user_steam_id: SteamId,
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GuideHit {
+    pub slug: &'static str,
+    pub title: &'static str,
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CommunityGuideHit {
+    pub id: i32,
+    pub title: String,
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserHit {
+    pub steam_id: SteamId,
+    pub persona_name: String,
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SearchResults {
+    pub query: String,
+    pub loadouts: Vec<LoadoutHit>,
+    pub guides: Vec<GuideHit>,
+    pub community_guides: Vec<CommunityGuideHit>,
+    pub users: Vec<UserHit>,
+}
+
+impl SearchResults {
+    pub fn is_empty(&self) -> bool {
+        self.loadouts.is_empty()
+            && self.guides.is_empty()
+            && self.community_guides.is_empty()
+            && self.users.is_empty()
+    }
+}
+
+pub fn search(
+    query: &str,
+    pool: &PgPool,
+) -> Box<dyn Future<Item = SearchResults, Error = app::Error>> {
+    let query = query.trim().to_owned();
+
+    let tsquery = match to_tsquery(&query) {
+        Some(tsquery) => tsquery,
+        None => {
+            return Box::new(future::ok(SearchResults {
+                query,
+                ..SearchResults::default()
+            }))
+        }
+    };
+
+    Box::new(
+        pool.connection()
+            .from_err::<app::Error>()
+            .and_then(move |mut conn| {
+                let conn = &mut *conn;
+                let options = HEADLINE_OPTIONS.as_str();
+                let titles = GUIDES.iter().map(|g| g.title).collect::<Vec<_>>();
+                let texts = GUIDES.iter().map(|g| g.text).collect::<Vec<_>>();
+
+                let loadouts = conn
+                    .query(|q| &q.search_loadouts, &[&tsquery, &LIMIT, &options])
+                    .collect();
+                let users = conn
+                    .query(|q| &q.search_users, &[&tsquery, &LIMIT, &options])
+                    .collect();
+                let guides = conn
+                    .query(
+                        |q| &q.search_guides,
+                        &[&tsquery, &LIMIT, &options, &titles, &texts],
+                    )
+                    .collect();
+                let community = conn
+                    .query(
+                        |q| &q.search_community_guides,
+                        &[&tsquery, &LIMIT, &options],
+                    )
+                    .collect();
+
+                loadouts.join4(guides, community, users).from_err()
+            })
+            .map(move |(loadouts, guides, community, users)| SearchResults {
+                query,
+                loadouts: loadouts
+                    .iter()
+                    .map(|row| LoadoutHit {
+                        id: row.get(0),
+                        name: row.get(1),
+                        user_steam_id: row.get(2),
+                        snippet: highlight(row.get(3)),
+                    })
+                    .collect(),
+                guides: guides
+                    .iter()
+                    .filter_map(|row| {
+                        let index = row.get::<_, i64>(0) as usize;
+                        GUIDES.get(index - 1).map(|guide| GuideHit {
+                            slug: guide.slug,
+                            title: guide.title,
+                            snippet: highlight(row.get(1)),
+                        })
+                    })
+                    .collect(),
+                community_guides: community
+                    .iter()
+                    .map(|row| CommunityGuideHit {
+                        id: row.get(0),
+                        title: row.get(1),
+                        snippet: highlight(row.get(2)),
+                    })
+                    .collect(),
+                users: users
+                    .iter()
+                    .map(|row| UserHit {
+                        steam_id: row.get(0),
+                        persona_name: row.get(1),
+                        snippet: highlight(row.get(2)),
+                    })
+                    .collect(),
+            }),
+    )
+}
+
+// Build a prefix-matching tsquery out of whatever the user typed, e.g
+// `long sword` into `long:* & sword:*`. Only letters and numbers are kept, so
+// the result is always valid tsquery syntax.
+fn to_tsquery(query: &str) -> Option<String> {
+    let terms = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .take(8)
+        .map(|term| format!("{}:*", term.to_lowercase()))
+        .collect::<Vec<_>>();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" & "))
+    }
+}
+
+// Escape a `ts_headline` snippet for use as html, with matches highlighted
+fn highlight(headline: &str) -> String {
+    headline
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace(START_SEL, "<mark>")
+        .replace(STOP_SEL, "</mark>")
+}
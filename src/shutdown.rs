@@ -0,0 +1,93 @@
+// Graceful shutdown. On SIGTERM or SIGINT:
+//
+//   1. `/readyz` starts failing, so that load balancers stop sending requests
+//   2. after `READINESS_DELAY`, the server stops accepting connections, and
+//      waits for in-flight requests to finish (up to the grace period given
+//      to `HttpServer::shutdown_timeout`)
+//   3. the database pools are closed, and the system is stopped
+//
+// A second signal skips straight to stopping the system.
+
+use crate::db;
+use actix_rt::System;
+use actix_web::dev::Server;
+use futures::{
+    future::{self, Either},
+    Future,
+    Stream,
+};
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+use tokio_timer::Delay;
+
+// Long enough for load balancers to notice `/readyz` failing
+const READINESS_DELAY: Duration = Duration::from_secs(5);
+
+const POOL_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+pub fn in_progress() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+// Shut down gracefully once a signal arrives. The server should have been
+// started with its own signal handling disabled.
+pub fn on_signal(server: Server, pool: db::Pool, replica: Option<db::Replica>) {
+    actix_rt::spawn(
+        signals()
+            .into_future()
+            .map_err(|(e, _)| error!("failed to listen for shutdown signals: {}", e))
+            .and_then(move |(_, signals)| {
+                actix_rt::spawn(signals.into_future().then(|_| {
+                    warn!("received a second signal, stopping immediately");
+                    System::current().stop();
+                    Ok(())
+                }));
+
+                shutdown(server, pool, replica)
+            }),
+    );
+}
+
+fn signals() -> impl Stream<Item = (), Error = io::Error> {
+    let term = Signal::new(SIGTERM).flatten_stream();
+    let int = Signal::new(SIGINT).flatten_stream();
+
+    term.select(int).map(|_| ())
+}
+
+fn shutdown(
+    server: Server,
+    pool: db::Pool,
+    replica: Option<db::Replica>,
+) -> impl Future<Item = (), Error = ()> {
+    info!(
+        "shutting down, no longer ready and waiting {}s before draining connections",
+        READINESS_DELAY.as_secs()
+    );
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+
+    Delay::new(Instant::now() + READINESS_DELAY)
+        .then(move |_| {
+            info!("stopped accepting connections, waiting for requests to finish");
+            server.stop(true)
+        })
+        .then(move |_| {
+            let replica = match replica {
+                Some(replica) => Either::A(replica.close(POOL_CLOSE_TIMEOUT)),
+                None => Either::B(future::ok(())),
+            };
+
+            pool.close(POOL_CLOSE_TIMEOUT).join(replica)
+        })
+        .then(|_| {
+            info!("shutdown complete");
+            System::current().stop();
+            Ok(())
+        })
+}
@@ -0,0 +1,67 @@
+// Lookups against the Steam Web API. These need `STEAM_API_KEY` to be set,
+// and are skipped when it isn't.
+
+use crate::{app, models::user::SteamId};
+use futures::{future, Future};
+use reqwest::r#async::Client;
+use url::Url;
+
+const PLAYER_SUMMARIES_URL: &str =
+    "https://api.steampowered.com/ISteamUser/GetPlayerSummaries/v0002/";
+
+lazy_static::lazy_static! {
+    static ref API_KEY: Option<String> = std::env::var("STEAM_API_KEY").ok();
+}
+
+#[derive(Debug, Deserialize)]
+struct PlayerSummaries {
+    response: PlayerSummariesResponse,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlayerSummariesResponse {
+    players: Vec<Player>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Player {
+    personaname: String,
+}
+
+// The user's current display name on Steam. Failures are logged rather than
+// returned, since they shouldn't stop anyone from logging in.
+pub fn persona_name(
+    client: &Client,
+    steam_id: SteamId,
+) -> Box<dyn Future<Item = Option<String>, Error = app::Error>> {
+    let key = match API_KEY.as_ref() {
+        Some(key) => key,
+        None => return Box::new(future::ok(None)),
+    };
+
+    let url = Url::parse_with_params(
+        PLAYER_SUMMARIES_URL,
+        &[("key", key.as_str()), ("steamids", &steam_id.to_string())],
+    )
+    .expect("invalid steam api url");
+
+    Box::new(
+        client
+            .get(url)
+            .send()
+            .and_then(|res| res.error_for_status())
+            .and_then(|mut res| res.json::<PlayerSummaries>())
+            .then(move |res| match res {
+                Ok(summaries) => Ok(summaries
+                    .response
+                    .players
+                    .into_iter()
+                    .next()
+                    .map(|p| p.personaname)),
+                Err(e) => {
+                    warn!("failed to get persona name for {}: {}", steam_id, e);
+                    Ok(None)
+                }
+            }),
+    )
+}